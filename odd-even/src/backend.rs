@@ -0,0 +1,183 @@
+//! The outside world the game depends on: the ICRC ledgers, the clock and the randomness
+//! source. `OddEven` is generic over these so the game flow can run off-chain against mocks.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use candid::{Nat, Principal};
+use ic_cdk::{
+    api::{canister_self, time},
+    management_canister::raw_rand,
+};
+use ic_cdk_timers::{set_timer, set_timer_interval};
+use icrc_ledger_client::ICRC1Client;
+use icrc_ledger_types::{
+    icrc1::account::Account,
+    icrc1::transfer::{TransferArg, TransferError},
+    icrc2::allowance::{Allowance, AllowanceArgs},
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+};
+
+use crate::runtime::CdkRuntime;
+
+/// Result of an inter-canister call, with the rejection code and message on failure.
+pub type CallResult<T> = Result<T, (i32, String)>;
+
+/// Number of `raw_rand` draws kept ready for `start`.
+const RANDOMNESS_BUFFER_SIZE: usize = 16;
+const RANDOMNESS_REFILL_SECS: u64 = 60;
+
+thread_local! {
+    // Unused `raw_rand` draws. Kept on the heap only: the buffer is secret and is simply
+    // refilled after an upgrade.
+    static RANDOMNESS: RefCell<VecDeque<Vec<u8>>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// The ICRC-1/ICRC-2 calls the game makes, on the ledger given with each call.
+pub trait Ledger {
+    /// The account of this canister, which holds the bankroll and receives the bets.
+    fn self_account(&self) -> Account;
+
+    async fn fee(&self, ledger: Principal) -> CallResult<Nat>;
+
+    async fn decimals(&self, ledger: Principal) -> CallResult<u8>;
+
+    async fn symbol(&self, ledger: Principal) -> CallResult<String>;
+
+    async fn balance_of(&self, ledger: Principal, account: Account) -> CallResult<Nat>;
+
+    async fn allowance(&self, ledger: Principal, args: AllowanceArgs) -> CallResult<Allowance>;
+
+    async fn transfer(
+        &self,
+        ledger: Principal,
+        args: TransferArg,
+    ) -> CallResult<Result<Nat, TransferError>>;
+
+    async fn transfer_from(
+        &self,
+        ledger: Principal,
+        args: TransferFromArgs,
+    ) -> CallResult<Result<Nat, TransferFromError>>;
+}
+
+pub trait Clock {
+    /// Nanoseconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+pub trait Randomness {
+    /// 32 fresh random bytes.
+    async fn random_bytes(&self) -> Result<Vec<u8>, String>;
+}
+
+/// Calls the ledgers with `ICRC1Client`.
+#[derive(Default)]
+pub struct IcLedger;
+
+impl IcLedger {
+    fn client(ledger: Principal) -> ICRC1Client<CdkRuntime> {
+        ICRC1Client {
+            runtime: CdkRuntime,
+            ledger_canister_id: ledger,
+        }
+    }
+}
+
+impl Ledger for IcLedger {
+    fn self_account(&self) -> Account {
+        Account {
+            owner: canister_self(),
+            subaccount: None,
+        }
+    }
+
+    async fn fee(&self, ledger: Principal) -> CallResult<Nat> {
+        Self::client(ledger).fee().await
+    }
+
+    async fn decimals(&self, ledger: Principal) -> CallResult<u8> {
+        Self::client(ledger).decimals().await
+    }
+
+    async fn symbol(&self, ledger: Principal) -> CallResult<String> {
+        Self::client(ledger).symbol().await
+    }
+
+    async fn balance_of(&self, ledger: Principal, account: Account) -> CallResult<Nat> {
+        Self::client(ledger).balance_of(account).await
+    }
+
+    async fn allowance(&self, ledger: Principal, args: AllowanceArgs) -> CallResult<Allowance> {
+        Self::client(ledger).allowance(args).await
+    }
+
+    async fn transfer(
+        &self,
+        ledger: Principal,
+        args: TransferArg,
+    ) -> CallResult<Result<Nat, TransferError>> {
+        Self::client(ledger).transfer(args).await
+    }
+
+    async fn transfer_from(
+        &self,
+        ledger: Principal,
+        args: TransferFromArgs,
+    ) -> CallResult<Result<Nat, TransferFromError>> {
+        Self::client(ledger).transfer_from(args).await
+    }
+}
+
+/// The IC system time.
+#[derive(Default)]
+pub struct IcClock;
+
+impl Clock for IcClock {
+    fn now(&self) -> u64 {
+        time()
+    }
+}
+
+/// `raw_rand`, served from a buffer refilled by a timer so most games skip the extra call.
+#[derive(Default)]
+pub struct IcRandomness;
+
+impl Randomness for IcRandomness {
+    /// Takes the oldest buffered draw and only calls `raw_rand` when the buffer is empty.
+    async fn random_bytes(&self) -> Result<Vec<u8>, String> {
+        if let Some(bytes) = RANDOMNESS.with_borrow_mut(|r| r.pop_front()) {
+            return Ok(bytes);
+        }
+
+        raw_rand().await.map_err(|err| err.to_string())
+    }
+}
+
+/// Fills the randomness buffer right away and then every `RANDOMNESS_REFILL_SECS`.
+pub fn schedule_randomness_refill() {
+    set_timer(Duration::ZERO, || {
+        ic_cdk::futures::spawn(refill_randomness())
+    });
+    set_timer_interval(Duration::from_secs(RANDOMNESS_REFILL_SECS), || {
+        ic_cdk::futures::spawn(refill_randomness())
+    });
+}
+
+async fn refill_randomness() {
+    while RANDOMNESS.with_borrow(|r| r.len()) < RANDOMNESS_BUFFER_SIZE {
+        match raw_rand().await {
+            // A concurrent refill may have filled the buffer during the call.
+            Ok(bytes) => RANDOMNESS.with_borrow_mut(|r| {
+                if r.len() < RANDOMNESS_BUFFER_SIZE {
+                    r.push_back(bytes)
+                }
+            }),
+            Err(err) => {
+                eprintln!("Refill randomness: {err}");
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::game::BPS;
+
+const HOUR_NANOS: u64 = 3_600_000_000_000;
+pub const DAY_NANOS: u64 = 24 * HOUR_NANOS;
+/// Most decimals a token can have: one whole token, `10^decimals`, must fit in a `u128`.
+pub const MAX_DECIMALS: u8 = 38;
+
+/// What happens to the bet of a game that expires without being played.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum ExpiryPolicy {
+    Refund,
+    Forfeit,
+}
+
+/// A token accepted for bets, with the bet limits and the wager caps in its base units.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenConfig {
+    pub symbol: String,
+    pub decimals: u8,
+    pub min_amount: u64,
+    pub max_amount: u64,
+    /// Most a player can wager in this token per UTC day. 0 means no cap.
+    pub daily_wager_limit: u64,
+    /// Most a player can wager in this token per 7-day week. 0 means no cap.
+    pub weekly_wager_limit: u64,
+}
+
+impl TokenConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.decimals > MAX_DECIMALS {
+            return Err(format!(
+                "decimals must not be greater than {MAX_DECIMALS}, got {}",
+                self.decimals
+            ));
+        }
+        if self.min_amount == 0 || self.min_amount > self.max_amount {
+            return Err("min_amount must be positive and not greater than max_amount".to_string());
+        }
+        if self.daily_wager_limit > 0
+            && self.weekly_wager_limit > 0
+            && self.daily_wager_limit > self.weekly_wager_limit
+        {
+            return Err(
+                "daily_wager_limit must not be greater than weekly_wager_limit".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Formats an amount in base units as a decimal string, e.g. `10_000_000` -> `0.1`.
+    pub fn format_amount(&self, amount: u64) -> String {
+        let scale = 10u128.pow(self.decimals as u32);
+        let whole = amount as u128 / scale;
+        let fraction = amount as u128 % scale;
+        if fraction == 0 {
+            return whole.to_string();
+        }
+
+        let fraction = format!("{fraction:0width$}", width = self.decimals as usize);
+        format!("{whole}.{}", fraction.trim_end_matches('0'))
+    }
+}
+
+/// Game settings. The ledger fields describe the default token; other tokens are added to
+/// the allow-list with `admin_add_token`. The symbol and decimals are read from the ledger.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub ledger_canister_id: Principal,
+    pub token_symbol: String,
+    pub token_decimals: u8,
+    pub min_amount: u64,
+    pub max_amount: u64,
+    pub expiry_nanos: u64,
+    pub expiry_policy: ExpiryPolicy,
+    /// Interval of the expired games sweep. 0 disables the timer.
+    pub sweep_interval_secs: u64,
+    /// Most a player can wager in the default token per UTC day. 0 means no cap.
+    pub daily_wager_limit: u64,
+    /// Most a player can wager in the default token per 7-day week. 0 means no cap.
+    pub weekly_wager_limit: u64,
+    /// Minimum time between two games started by the same player.
+    pub cooldown_secs: u64,
+    /// Share of every losing wager added to the jackpot, in basis points. Games without a house
+    /// edge do not contribute.
+    pub jackpot_bps: u64,
+    /// A played game wins the jackpot with a chance of 1 in `jackpot_odds`. 0 disables the draw.
+    pub jackpot_odds: u64,
+    /// Share of the house edge on a referred player's losing bet credited to their referrer,
+    /// in basis points. `OddEven` has no house edge, so its bets earn no reward.
+    pub referral_bps: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ledger_canister_id: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+            token_symbol: "ICP".to_string(),
+            token_decimals: 8,
+            min_amount: 10_000_000,
+            max_amount: 500_000_000,
+            expiry_nanos: 7 * DAY_NANOS,
+            expiry_policy: ExpiryPolicy::Refund,
+            sweep_interval_secs: 3_600,
+            daily_wager_limit: 0,
+            weekly_wager_limit: 0,
+            cooldown_secs: 10,
+            jackpot_bps: 100,
+            jackpot_odds: 10_000,
+            referral_bps: 2_000,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Default)]
+pub struct ConfigUpdate {
+    pub ledger_canister_id: Option<Principal>,
+    pub min_amount: Option<u64>,
+    pub max_amount: Option<u64>,
+    pub expiry_nanos: Option<u64>,
+    pub expiry_policy: Option<ExpiryPolicy>,
+    pub sweep_interval_secs: Option<u64>,
+    pub daily_wager_limit: Option<u64>,
+    pub weekly_wager_limit: Option<u64>,
+    pub cooldown_secs: Option<u64>,
+    pub jackpot_bps: Option<u64>,
+    pub jackpot_odds: Option<u64>,
+    pub referral_bps: Option<u64>,
+}
+
+impl Config {
+    /// Returns a copy of the config with the fields set in `update` replaced.
+    pub fn apply(&self, update: ConfigUpdate) -> Result<Config, String> {
+        let config = Config {
+            ledger_canister_id: update.ledger_canister_id.unwrap_or(self.ledger_canister_id),
+            token_symbol: self.token_symbol.clone(),
+            token_decimals: self.token_decimals,
+            min_amount: update.min_amount.unwrap_or(self.min_amount),
+            max_amount: update.max_amount.unwrap_or(self.max_amount),
+            expiry_nanos: update.expiry_nanos.unwrap_or(self.expiry_nanos),
+            expiry_policy: update.expiry_policy.unwrap_or(self.expiry_policy),
+            sweep_interval_secs: update
+                .sweep_interval_secs
+                .unwrap_or(self.sweep_interval_secs),
+            daily_wager_limit: update.daily_wager_limit.unwrap_or(self.daily_wager_limit),
+            weekly_wager_limit: update.weekly_wager_limit.unwrap_or(self.weekly_wager_limit),
+            cooldown_secs: update.cooldown_secs.unwrap_or(self.cooldown_secs),
+            jackpot_bps: update.jackpot_bps.unwrap_or(self.jackpot_bps),
+            jackpot_odds: update.jackpot_odds.unwrap_or(self.jackpot_odds),
+            referral_bps: update.referral_bps.unwrap_or(self.referral_bps),
+        };
+
+        config.default_token().validate()?;
+
+        if config.expiry_nanos == 0 {
+            return Err("expiry_nanos must be positive".to_string());
+        }
+
+        if config.jackpot_bps > BPS {
+            return Err("jackpot_bps must not be greater than 10000".to_string());
+        }
+
+        if config.referral_bps > BPS {
+            return Err("referral_bps must not be greater than 10000".to_string());
+        }
+
+        Ok(config)
+    }
+
+    pub fn default_token(&self) -> TokenConfig {
+        TokenConfig {
+            symbol: self.token_symbol.clone(),
+            decimals: self.token_decimals,
+            min_amount: self.min_amount,
+            max_amount: self.max_amount,
+            daily_wager_limit: self.daily_wager_limit,
+            weekly_wager_limit: self.weekly_wager_limit,
+        }
+    }
+
+    /// Formats an amount of the default token, see [`TokenConfig::format_amount`].
+    pub fn format_amount(&self, amount: u64) -> String {
+        self.default_token().format_amount(amount)
+    }
+
+    pub fn format_expiry(&self) -> String {
+        if self.expiry_nanos.is_multiple_of(DAY_NANOS) {
+            format!("{} day(s)", self.expiry_nanos / DAY_NANOS)
+        } else if self.expiry_nanos.is_multiple_of(HOUR_NANOS) {
+            format!("{} hour(s)", self.expiry_nanos / HOUR_NANOS)
+        } else {
+            format!("{} second(s)", self.expiry_nanos / 1_000_000_000)
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use candid::CandidType;
+use hmac::{Hmac, Mac};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// Multipliers and edges are expressed in basis points.
+pub const BPS: u64 = 10_000;
+
+/// Scales `amount` by a multiplier in basis points.
+pub fn apply_bps(amount: u64, bps: u64) -> u64 {
+    u64::try_from(amount as u128 * bps as u128 / BPS as u128).unwrap_or(u64::MAX)
+}
+
+/// A game of chance resolved from a random draw.
+pub trait Game {
+    /// Every outcome a draw can produce.
+    fn outcomes(&self) -> Vec<String>;
+
+    /// Every guess a player can make.
+    fn guesses(&self) -> Vec<String>;
+
+    /// Derives an outcome from the bytes returned by `raw_rand`.
+    fn draw(&self, bytes: &[u8]) -> String;
+
+    /// Payout multiplier of `guess` given `outcome`, 0 if the guess loses.
+    fn payout_bps(&self, guess: &str, outcome: &str) -> u64;
+
+    /// Largest multiplier any guess can win.
+    fn max_payout_bps(&self) -> u64;
+
+    /// Expected share of each bet kept by the house.
+    fn house_edge_bps(&self) -> u64;
+
+    fn rules(&self) -> &'static str;
+}
+
+#[derive(
+    CandidType, JsonSchema, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default,
+)]
+pub enum GameType {
+    #[default]
+    OddEven,
+    Dice,
+    OverUnder,
+}
+
+impl GameType {
+    pub const ALL: [GameType; 3] = [GameType::OddEven, GameType::Dice, GameType::OverUnder];
+
+    pub fn game(&self) -> &'static dyn Game {
+        match self {
+            GameType::OddEven => &OddEven,
+            GameType::Dice => &Dice,
+            GameType::OverUnder => &OverUnder,
+        }
+    }
+
+    /// The server seed committed to by the game hash. Games eligible for the jackpot also
+    /// commit to its odds.
+    pub fn server_seed(&self, random_hex: &str, jackpot_odds: Option<u64>) -> String {
+        match jackpot_odds {
+            Some(odds) => format!("{self}|{random_hex}|{odds}"),
+            None => format!("{self}|{random_hex}"),
+        }
+    }
+
+    /// Splits a server seed produced by [`GameType::server_seed`] into its parts.
+    pub fn parse_server_seed(server_seed: &str) -> Option<(GameType, &str, Option<u64>)> {
+        let mut parts = server_seed.split('|');
+        let game_type = parts.next()?.parse().ok()?;
+        let random_hex = parts.next()?;
+        let jackpot_odds = match parts.next() {
+            Some(odds) => Some(odds.parse().ok().filter(|odds| *odds > 0)?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some((game_type, random_hex, jackpot_odds))
+    }
+
+    /// Draws the outcome from `HMAC-SHA256(random_hex, client_seed)`, keyed with the ASCII
+    /// bytes of the committed random hex.
+    pub fn draw_seeded(&self, random_hex: &str, client_seed: &str) -> String {
+        self.game().draw(&seeded_bytes(random_hex, client_seed))
+    }
+
+    /// The plaintext committed to by games started before client seeds existed, whose result
+    /// was drawn at `start`. Odd-even keeps the original `<Result>|<Random_Hex>` template so
+    /// hashes of older games still verify; other games prefix the game type.
+    pub fn plaintext(&self, outcome: &str, random_hex: &str) -> String {
+        match self {
+            GameType::OddEven => format!("{outcome}|{random_hex}"),
+            _ => format!("{self}|{outcome}|{random_hex}"),
+        }
+    }
+
+    /// Splits a plaintext produced by [`GameType::plaintext`] into its parts.
+    pub fn parse_plaintext(plaintext: &str) -> Option<(GameType, &str, &str)> {
+        let mut parts = plaintext.split('|');
+        let (game_type, outcome, random_hex) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(outcome), Some(random_hex), None) => (GameType::OddEven, outcome, random_hex),
+            (Some(game_type), Some(outcome), Some(random_hex)) => {
+                (game_type.parse().ok()?, outcome, random_hex)
+            }
+            _ => return None,
+        };
+
+        if parts.next().is_some() || !game_type.game().outcomes().iter().any(|o| o == outcome) {
+            return None;
+        }
+
+        Some((game_type, outcome, random_hex))
+    }
+}
+
+impl std::fmt::Display for GameType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GameType::OddEven => write!(f, "OddEven"),
+            GameType::Dice => write!(f, "Dice"),
+            GameType::OverUnder => write!(f, "OverUnder"),
+        }
+    }
+}
+
+impl std::str::FromStr for GameType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        GameType::ALL
+            .into_iter()
+            .find(|game_type| game_type.to_string() == s)
+            .ok_or(format!("invalid game type: {s}"))
+    }
+}
+
+/// Whether a game wins the jackpot: the last 8 bytes of `HMAC-SHA256(random_hex, client_seed)`,
+/// read as a little-endian integer, are a multiple of `odds`. Dice and over/under read the first
+/// 16 bytes only. The odd-even parity XORs all 32, but the low bits of the first 24 are uniform
+/// and independent of the last 8, so the parity is too and the jackpot draw is independent of
+/// the game outcome.
+pub fn jackpot_hit(random_hex: &str, client_seed: &str, odds: u64) -> bool {
+    let bytes = seeded_bytes(random_hex, client_seed);
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&bytes[24..32]);
+    odds > 0 && u64::from_le_bytes(buf).is_multiple_of(odds)
+}
+
+fn seeded_bytes(random_hex: &str, client_seed: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(random_hex.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(client_seed.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Reads a die roll (1-6) from 8 bytes of randomness.
+fn roll(bytes: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    for (b, byte) in buf.iter_mut().zip(bytes) {
+        *b = *byte;
+    }
+    u64::from_le_bytes(buf) % 6 + 1
+}
+
+/// Guess the parity of the draw. Pays 2x.
+struct OddEven;
+
+impl Game for OddEven {
+    fn outcomes(&self) -> Vec<String> {
+        vec!["Odd".to_string(), "Even".to_string()]
+    }
+
+    fn guesses(&self) -> Vec<String> {
+        self.outcomes()
+    }
+
+    fn draw(&self, bytes: &[u8]) -> String {
+        let parity = bytes.iter().fold(0u8, |acc, &b| acc ^ b) & 1;
+        if parity == 0 { "Even" } else { "Odd" }.to_string()
+    }
+
+    fn payout_bps(&self, guess: &str, outcome: &str) -> u64 {
+        if guess == outcome {
+            2 * BPS
+        } else {
+            0
+        }
+    }
+
+    fn max_payout_bps(&self) -> u64 {
+        2 * BPS
+    }
+
+    fn house_edge_bps(&self) -> u64 {
+        0
+    }
+
+    fn rules(&self) -> &'static str {
+        "guess `Odd` or `Even`, pays 2x"
+    }
+}
+
+/// Guess the exact roll of a die. Pays 5.7x.
+struct Dice;
+
+impl Game for Dice {
+    fn outcomes(&self) -> Vec<String> {
+        (1..=6).map(|face| face.to_string()).collect()
+    }
+
+    fn guesses(&self) -> Vec<String> {
+        self.outcomes()
+    }
+
+    fn draw(&self, bytes: &[u8]) -> String {
+        roll(bytes).to_string()
+    }
+
+    fn payout_bps(&self, guess: &str, outcome: &str) -> u64 {
+        if guess == outcome {
+            57_000
+        } else {
+            0
+        }
+    }
+
+    fn max_payout_bps(&self) -> u64 {
+        57_000
+    }
+
+    fn house_edge_bps(&self) -> u64 {
+        500
+    }
+
+    fn rules(&self) -> &'static str {
+        "guess the face of a die, `1` to `6`, pays 5.7x"
+    }
+}
+
+/// Guess whether the sum of two dice is over, under or exactly 7. Over and under pay 2x,
+/// seven pays 5x.
+struct OverUnder;
+
+impl Game for OverUnder {
+    fn outcomes(&self) -> Vec<String> {
+        (2..=12).map(|sum| sum.to_string()).collect()
+    }
+
+    fn guesses(&self) -> Vec<String> {
+        vec!["Over".to_string(), "Under".to_string(), "Seven".to_string()]
+    }
+
+    fn draw(&self, bytes: &[u8]) -> String {
+        let second = bytes.get(8..).unwrap_or_default();
+        (roll(bytes) + roll(second)).to_string()
+    }
+
+    fn payout_bps(&self, guess: &str, outcome: &str) -> u64 {
+        let Ok(sum) = outcome.parse::<u64>() else {
+            return 0;
+        };
+
+        match guess {
+            "Over" if sum > 7 => 2 * BPS,
+            "Under" if sum < 7 => 2 * BPS,
+            "Seven" if sum == 7 => 5 * BPS,
+            _ => 0,
+        }
+    }
+
+    fn max_payout_bps(&self) -> u64 {
+        5 * BPS
+    }
+
+    fn house_edge_bps(&self) -> u64 {
+        1_667
+    }
+
+    fn rules(&self) -> &'static str {
+        "two dice are rolled; guess `Over` or `Under` 7 (pays 2x) or `Seven` (pays 5x)"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 32 bytes, zero except for `(index, value)` pairs.
+    fn bytes(set: &[(usize, u8)]) -> Vec<u8> {
+        let mut bytes = vec![0; 32];
+        for (index, value) in set {
+            bytes[*index] = *value;
+        }
+        bytes
+    }
+
+    /// Draws covering every outcome of `game_type` with its probability: each die face, or
+    /// each pair of faces, once; each parity once.
+    fn uniform_draws(game_type: GameType) -> Vec<Vec<u8>> {
+        match game_type {
+            GameType::OddEven => vec![bytes(&[]), bytes(&[(31, 1)])],
+            GameType::Dice => (0..6).map(|face| bytes(&[(0, face)])).collect(),
+            GameType::OverUnder => (0..6)
+                .flat_map(|first| (0..6).map(move |second| bytes(&[(0, first), (8, second)])))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn draws() {
+        let cases: [(GameType, Vec<u8>, &str); 10] = [
+            (GameType::OddEven, bytes(&[]), "Even"),
+            (GameType::OddEven, bytes(&[(5, 1)]), "Odd"),
+            (GameType::OddEven, bytes(&[(5, 1), (30, 3)]), "Even"),
+            (GameType::OddEven, bytes(&[(0, 2), (31, 0xff)]), "Odd"),
+            (GameType::Dice, bytes(&[]), "1"),
+            (GameType::Dice, bytes(&[(0, 5)]), "6"),
+            (GameType::Dice, bytes(&[(0, 6), (8, 4)]), "1"),
+            (GameType::OverUnder, bytes(&[]), "2"),
+            (GameType::OverUnder, bytes(&[(0, 2), (8, 3)]), "7"),
+            (GameType::OverUnder, bytes(&[(0, 5), (8, 5)]), "12"),
+        ];
+        for (game_type, bytes, outcome) in cases {
+            assert_eq!(game_type.game().draw(&bytes), outcome, "{game_type}");
+        }
+    }
+
+    #[test]
+    fn draws_only_produce_outcomes() {
+        for game_type in GameType::ALL {
+            let game = game_type.game();
+            for bytes in uniform_draws(game_type) {
+                assert!(game.outcomes().contains(&game.draw(&bytes)), "{game_type}");
+            }
+        }
+    }
+
+    #[test]
+    fn payouts() {
+        let cases = [
+            (GameType::OddEven, "Odd", "Odd", 20_000),
+            (GameType::OddEven, "Even", "Even", 20_000),
+            (GameType::OddEven, "Odd", "Even", 0),
+            (GameType::Dice, "4", "4", 57_000),
+            (GameType::Dice, "4", "5", 0),
+            (GameType::OverUnder, "Over", "8", 20_000),
+            (GameType::OverUnder, "Over", "7", 0),
+            (GameType::OverUnder, "Under", "6", 20_000),
+            (GameType::OverUnder, "Under", "12", 0),
+            (GameType::OverUnder, "Seven", "7", 50_000),
+            (GameType::OverUnder, "Seven", "2", 0),
+            (GameType::OverUnder, "Over", "not a sum", 0),
+        ];
+        for (game_type, guess, outcome, payout_bps) in cases {
+            assert_eq!(
+                game_type.game().payout_bps(guess, outcome),
+                payout_bps,
+                "{game_type} {guess} {outcome}"
+            );
+        }
+    }
+
+    #[test]
+    fn max_payout_is_the_largest_payout() {
+        for game_type in GameType::ALL {
+            let game = game_type.game();
+            let max = game
+                .guesses()
+                .iter()
+                .flat_map(|guess| {
+                    game.outcomes()
+                        .into_iter()
+                        .map(move |outcome| game.payout_bps(guess, &outcome))
+                })
+                .max();
+            assert_eq!(max, Some(game.max_payout_bps()), "{game_type}");
+        }
+    }
+
+    /// The house edge is what every guess loses on average, to the basis point.
+    #[test]
+    fn house_edge_matches_the_odds() {
+        for game_type in GameType::ALL {
+            let game = game_type.game();
+            let draws = uniform_draws(game_type);
+            for guess in game.guesses() {
+                let paid: u64 = draws
+                    .iter()
+                    .map(|bytes| game.payout_bps(&guess, &game.draw(bytes)))
+                    .sum();
+                let edge = BPS as f64 - paid as f64 / draws.len() as f64;
+                assert!(
+                    (edge - game.house_edge_bps() as f64).abs() < 1.0,
+                    "{game_type} {guess}: {edge}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn applies_bps() {
+        assert_eq!(apply_bps(100_000_000, 20_000), 200_000_000);
+        assert_eq!(apply_bps(100_000_000, 57_000), 570_000_000);
+        assert_eq!(apply_bps(100_000_000, 100), 1_000_000);
+        assert_eq!(apply_bps(99, 100), 0);
+        assert_eq!(apply_bps(u64::MAX, 2 * BPS), u64::MAX);
+    }
+
+    #[test]
+    fn seeded_draws() {
+        let cases = [
+            ("07", "seed", GameType::OddEven, "Even"),
+            ("07", "seed", GameType::Dice, "6"),
+            ("07", "seed", GameType::OverUnder, "11"),
+            ("ab", "", GameType::OddEven, "Even"),
+            ("ab", "", GameType::Dice, "3"),
+            ("ab", "", GameType::OverUnder, "9"),
+            ("00", "client", GameType::OddEven, "Odd"),
+            ("00", "client", GameType::Dice, "5"),
+            ("00", "client", GameType::OverUnder, "10"),
+            ("07", "lucky", GameType::OverUnder, "10"),
+        ];
+        for (byte, client_seed, game_type, outcome) in cases {
+            let random_hex = byte.repeat(32);
+            assert_eq!(
+                game_type.draw_seeded(&random_hex, client_seed),
+                outcome,
+                "{game_type} {random_hex} {client_seed:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn jackpot_draws() {
+        // The last 8 bytes of HMAC-SHA256("07" * 32, "seed") are 1592025629648937212 and those
+        // of HMAC-SHA256("00" * 32, "client") are 325328559965628834.
+        let cases = [
+            ("07", "seed", 1, true),
+            ("07", "seed", 4, true),
+            ("07", "seed", 3, false),
+            ("07", "seed", 1_592_025_629_648_937_212, true),
+            ("07", "seed", 10_000, false),
+            ("00", "client", 3, true),
+            ("00", "client", 4, false),
+            ("00", "client", 0, false),
+        ];
+        for (byte, client_seed, odds, hit) in cases {
+            assert_eq!(
+                jackpot_hit(&byte.repeat(32), client_seed, odds),
+                hit,
+                "{byte} {client_seed:?} {odds}"
+            );
+        }
+    }
+
+    #[test]
+    fn server_seeds_round_trip() {
+        for (game_type, odds) in [
+            (GameType::OddEven, None),
+            (GameType::Dice, Some(10_000)),
+            (GameType::OverUnder, Some(1)),
+        ] {
+            let server_seed = game_type.server_seed("ab12", odds);
+            assert_eq!(
+                GameType::parse_server_seed(&server_seed),
+                Some((game_type, "ab12", odds))
+            );
+        }
+        assert_eq!(GameType::OddEven.server_seed("ab12", None), "OddEven|ab12");
+        assert_eq!(
+            GameType::Dice.server_seed("ab12", Some(100)),
+            "Dice|ab12|100"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_server_seeds() {
+        for server_seed in [
+            "",
+            "OddEven",
+            "Poker|ab12",
+            "oddeven|ab12",
+            "Dice|ab12|0",
+            "Dice|ab12|-1",
+            "Dice|ab12|x",
+            "Dice|ab12|100|1",
+        ] {
+            assert_eq!(
+                GameType::parse_server_seed(server_seed),
+                None,
+                "{server_seed}"
+            );
+        }
+    }
+
+    #[test]
+    fn plaintexts_round_trip() {
+        for (game_type, outcome) in [
+            (GameType::OddEven, "Odd"),
+            (GameType::Dice, "4"),
+            (GameType::OverUnder, "12"),
+        ] {
+            let plaintext = game_type.plaintext(outcome, "ab12");
+            assert_eq!(
+                GameType::parse_plaintext(&plaintext),
+                Some((game_type, outcome, "ab12"))
+            );
+        }
+        assert_eq!(GameType::OddEven.plaintext("Even", "ab12"), "Even|ab12");
+        assert_eq!(GameType::Dice.plaintext("4", "ab12"), "Dice|4|ab12");
+    }
+
+    #[test]
+    fn rejects_invalid_plaintexts() {
+        for plaintext in [
+            "",
+            "ab12",
+            "Seven|ab12",
+            "odd|ab12",
+            "Dice|7|ab12",
+            "OverUnder|1|ab12",
+            "Poker|4|ab12",
+            "Dice|4|ab12|x",
+        ] {
+            assert_eq!(GameType::parse_plaintext(plaintext), None, "{plaintext}");
+        }
+    }
+
+    #[test]
+    fn readme_verification_example() {
+        let random_hex = "3a7c9e1b".repeat(8);
+        let draw = |game_type: GameType| game_type.draw_seeded(&random_hex, "lucky-seed");
+
+        assert_eq!(draw(GameType::OddEven), "Even");
+        assert_eq!(draw(GameType::Dice), "4");
+        assert_eq!(draw(GameType::OverUnder), "6");
+        assert!(!jackpot_hit(&random_hex, "lucky-seed", 10_000));
+        assert!(jackpot_hit(&random_hex, "lucky-seed", 5));
+    }
+}
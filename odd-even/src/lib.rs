@@ -1,35 +1,75 @@
 use candid::{CandidType, Nat, Principal};
 use hex::encode;
 use ic_cdk::{
-    api::{canister_self, is_controller, msg_caller, time},
-    init,
-    management_canister::raw_rand,
-    query, update,
+    api::{is_controller, msg_caller, time},
+    init, post_upgrade, query, update,
 };
+use ic_cdk_timers::{clear_timer, set_timer, set_timer_interval, TimerId};
 use ic_http_certification::{HttpRequest, HttpResponse, StatusCode};
 use ic_rmcp::{
     model::*, schema_for_type, Context, Error, Handler, IssuerConfig, OAuthConfig, Server,
 };
-use icrc_ledger_client::ICRC1Client;
 use icrc_ledger_types::{
-    icrc1::account::Account, icrc1::transfer::TransferArg, icrc2::transfer_from::TransferFromArgs,
+    icrc1::account::Account,
+    icrc1::transfer::{Memo, TransferArg, TransferError},
+    icrc2::allowance::AllowanceArgs,
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
 };
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{from_value, Value};
 use sha2::{Digest, Sha256};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+mod backend;
+use backend::{
+    schedule_randomness_refill, CallResult, Clock, IcClock, IcLedger, IcRandomness, Ledger,
+    Randomness,
+};
+
+mod config;
+use config::{Config, ConfigUpdate, ExpiryPolicy, TokenConfig, DAY_NANOS};
+
+mod game;
+use game::{apply_bps, GameType, BPS};
 
 mod repo;
-use repo::{delete, get, insert};
+use repo::{
+    add_paid_out, add_referral_reward, add_to_jackpot, add_wagered, append_audit, archive,
+    audit_log, close_referral_claim, create, expired_games, find_game, find_record, get,
+    get_config, get_jackpot, get_player, get_referrer, get_stats, get_token, get_totals, history,
+    insert, leaderboard, list_games, migrate_game_schema, migrate_legacy_games, open_exposure,
+    open_games, open_referral_claim, pending_payouts, pending_referral_claim, player_games,
+    player_history, rebuild_stats_batch, referral_rewards, referral_rewards_owed,
+    refresh_referral_claim, remove_token, seed_open_totals, set_config, set_player, set_referrer,
+    set_token, start_stats_rebuild, take_jackpot, tokens,
+};
 
 mod runtime;
-use runtime::CdkRuntime;
 
-use crate::repo::GameInfo;
+use crate::repo::{
+    AuditEntry, GameId, GameInfo, GameRecord, GameState, JackpotWin, PlayerActivity,
+};
+
+const MAX_SELF_EXCLUSION_DAYS: u32 = 3_650;
+const MAX_OPEN_GAMES_PER_PLAYER: usize = 10;
+/// Most entries returned by one call of the admin list and export endpoints.
+const MAX_ADMIN_PAGE_SIZE: u64 = 1_000;
+/// History records counted into the statistics per timer call of a rebuild.
+const STATS_REBUILD_BATCH: usize = 500;
 
 thread_local! {
     static ARGS : RefCell<InitArgs> =  RefCell::default();
+    static SWEEP_TIMER: Cell<Option<TimerId>> = const { Cell::new(None) };
+    static SWEEPING: Cell<bool> = const { Cell::new(false) };
+    // Exposure of the bets `start` accepted while their deposit is in flight, by ledger.
+    static PENDING_EXPOSURE: RefCell<BTreeMap<Principal, u64>> = RefCell::default();
+    // Number of those bets by player.
+    static PENDING_GAMES: RefCell<BTreeMap<Principal, usize>> = RefCell::default();
+    // Number of transfers of referral claims in flight, by (referrer, ledger).
+    static CLAIM_ATTEMPTS: RefCell<BTreeMap<(Principal, Principal), usize>> = RefCell::default();
 }
 
 #[derive(Deserialize, CandidType, Default)]
@@ -41,41 +81,321 @@ struct InitArgs {
     authorization_server: Vec<String>,
     audience: String,
     scopes: Vec<String>,
+    config: Option<ConfigUpdate>,
 }
 
 #[init]
-fn init(config: InitArgs) {
-    ARGS.with_borrow_mut(|args| *args = config);
+fn init(mut args: InitArgs) {
+    let update = args.config.take().unwrap_or_default();
+    let ledger_changed = update.ledger_canister_id.is_some();
+    let config = Config::default()
+        .apply(update)
+        .unwrap_or_else(|err| ic_cdk::trap(err));
+    schedule_sweep(config.sweep_interval_secs);
+    schedule_randomness_refill();
+    set_config(config);
+    if ledger_changed {
+        schedule_token_metadata_refresh();
+    }
+    migrate_game_schema();
+    ARGS.with_borrow_mut(|a| *a = args);
+}
+
+#[post_upgrade]
+fn post_upgrade(mut args: InitArgs) {
+    if let Some(update) = args.config.take() {
+        let ledger_changed = update.ledger_canister_id.is_some();
+        set_config(
+            get_config()
+                .apply(update)
+                .unwrap_or_else(|err| ic_cdk::trap(err)),
+        );
+        if ledger_changed {
+            schedule_token_metadata_refresh();
+        }
+    }
+    seed_open_totals();
+    migrate_legacy_games();
+    migrate_game_schema();
+    if start_stats_rebuild() {
+        schedule_stats_rebuild();
+    }
+    schedule_sweep(get_config().sweep_interval_secs);
+    schedule_randomness_refill();
+    ARGS.with_borrow_mut(|a| *a = args);
 }
 
 #[derive(JsonSchema, Deserialize)]
 struct StartRequest {
     amount: u64,
+    /// Defaults to `OddEven`.
+    game_type: Option<GameType>,
+    /// Ledger of the token to bet in. Defaults to the default token.
+    ledger_canister_id: Option<String>,
+    /// Principal of the player who referred you. Only taken into account on your first game.
+    referrer: Option<String>,
 }
 
-#[derive(JsonSchema, Deserialize)]
-enum GameResult {
-    Odd,
-    Even,
+#[derive(Serialize, JsonSchema, Default)]
+enum StartStatus {
+    Started,
+    /// The allowance does not cover the bet plus the ledger fee; nothing was pulled.
+    ApprovalRequired,
+    #[default]
+    Rejected,
 }
 
-impl std::fmt::Display for GameResult {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            GameResult::Odd => write!(f, "Odd"),
-            GameResult::Even => write!(f, "Even"),
-        }
+/// JSON result of `start`. Only `status` and `reason` are set unless the game started, except
+/// for the allowance fields of an `ApprovalRequired` result.
+#[derive(Serialize, JsonSchema, Default)]
+struct StartResult {
+    status: StartStatus,
+    /// Why the game was not started.
+    reason: Option<String>,
+    game_id: Option<GameId>,
+    game_type: Option<GameType>,
+    ledger_canister_id: Option<String>,
+    /// Bet in base units of the token.
+    amount: Option<u64>,
+    /// SHA256 of the server seed, revealed by `play`.
+    hash: Option<String>,
+    /// Ledger block of the deposit.
+    deposit_block_index: Option<u64>,
+    /// Nanoseconds since the Unix epoch.
+    started_at_nanos: Option<u64>,
+    /// The game can not be played after this time (nanoseconds since the Unix epoch).
+    expires_at_nanos: Option<u64>,
+    referrer: Option<String>,
+    /// Bet plus the ledger fee, to approve with `icrc2_approve`.
+    required_allowance: Option<u128>,
+    current_allowance: Option<u128>,
+}
+
+impl StartResult {
+    fn rejected(reason: impl Into<String>) -> Result<CallToolResult, Error> {
+        let reason = reason.into();
+        text_and_json(
+            reason.clone(),
+            StartResult {
+                reason: Some(reason),
+                ..Default::default()
+            },
+        )
     }
 }
 
 #[derive(JsonSchema, Deserialize)]
 struct PlayRequest {
-    guess: GameResult,
+    /// Defaults to your oldest game waiting for a guess.
+    game_id: Option<u64>,
+    /// `Odd` or `Even` for `OddEven`, `1` to `6` for `Dice`, `Over`, `Under` or `Seven` for `OverUnder`.
+    guess: String,
+    /// Any string mixed into the outcome with `HMAC-SHA256(Random_Hex, client_seed)`. Defaults to an empty string.
+    client_seed: Option<String>,
+}
+
+#[derive(Serialize, JsonSchema, Default)]
+enum PlayStatus {
+    /// The payout was sent.
+    Paid,
+    /// Nothing to pay.
+    Lost,
+    /// The payout transfer failed; call `retry_payout`.
+    PayoutPending,
+    /// The game expired before it was played and is refunded or forfeited.
+    Expired,
+    #[default]
+    Rejected,
+}
+
+/// JSON result of `play`. A `Rejected` result only sets `status` and `reason`.
+#[derive(Serialize, JsonSchema, Default)]
+struct PlayResult {
+    status: PlayStatus,
+    /// Why the game was not played, or why the payout is pending.
+    reason: Option<String>,
+    game_id: Option<GameId>,
+    game_type: Option<GameType>,
+    hash: Option<String>,
+    guess: Option<String>,
+    result: Option<String>,
+    /// Whether the guess won. A lost guess can still be paid a jackpot.
+    won: Option<bool>,
+    /// Winnings plus any jackpot, in base units of the token.
+    payout: Option<u64>,
+    jackpot_won: Option<u64>,
+    /// Ledger block of the payout.
+    block_index: Option<u64>,
+    /// The committed plaintext: pass it with `client_seed` to `verify_game`.
+    plaintext: Option<String>,
+    client_seed: Option<String>,
+}
+
+impl PlayResult {
+    fn rejected(reason: impl Into<String>) -> Result<CallToolResult, Error> {
+        let reason = reason.into();
+        text_and_json(
+            reason.clone(),
+            PlayResult {
+                reason: Some(reason),
+                ..Default::default()
+            },
+        )
+    }
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct GetRequiredApprovalRequest {
+    amount: u64,
+    /// Defaults to the default token.
+    ledger_canister_id: Option<String>,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct LedgerRequest {
+    /// Defaults to the default token.
+    ledger_canister_id: Option<String>,
+}
+
+/// What a player must approve with `icrc2_approve` before `start` can pull a bet of `amount`.
+#[derive(Serialize)]
+struct RequiredApproval {
+    ledger_canister_id: Principal,
+    spender: Account,
+    amount: u64,
+    fee: u128,
+    required_allowance: u128,
+    current_allowance: u128,
+    expires_at: Option<u64>,
+    sufficient: bool,
+    message: String,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct SelfExcludeRequest {
+    /// Number of days, from 1 to 3650.
+    days: u32,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct GetGameHistoryRequest {
+    offset: Option<u64>,
+    limit: Option<u8>,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct VerifyGameRequest {
+    hash: String,
+    plaintext: String,
+    client_seed: Option<String>,
+}
+
+#[derive(CandidType, Serialize)]
+struct GameVerification {
+    verified: bool,
+    hash_matches: bool,
+    record_matches: bool,
+    computed_hash: Option<String>,
+    computed_result: Option<String>,
+    computed_jackpot_hit: Option<bool>,
+    record: Option<GameRecord>,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct MyGame {
+    game_id: GameId,
+    game_type: GameType,
+    ledger_canister_id: Principal,
+    amount: u64,
+    hash: String,
+    state: GameState,
+    started_at_nanos: u64,
+    expires_at_nanos: u64,
+}
+
+#[derive(Serialize)]
+struct HouseStats {
+    ledger_canister_id: Principal,
+    balance: u128,
+    open_games: u64,
+    open_exposure: u64,
+    jackpot: u64,
+    referral_rewards: u64,
+    bankroll: i128,
+    total_wagered: u64,
+    total_paid_out: u64,
+}
+
+#[derive(Serialize)]
+struct JackpotInfo {
+    ledger_canister_id: Principal,
+    pool: u64,
+    odds: u64,
+    contribution_bps: u64,
+    total_contributed: u64,
+    total_paid_out: u64,
+    wins: u64,
+    last_win: Option<JackpotWin>,
+}
+
+const LEADERBOARD_URI: &str = "oddeven://leaderboard";
+const STATS_URI: &str = "oddeven://stats";
+const LEADERBOARD_SIZE: usize = 10;
+
+#[derive(Serialize)]
+struct LeaderboardEntry {
+    rank: usize,
+    player: Principal,
+    net_profit: i64,
+    games: u64,
+    wins: u64,
+    win_rate: f64,
+    wagered: u64,
+}
+
+#[derive(Serialize)]
+struct OutcomeShare {
+    game_type: GameType,
+    result: String,
+    count: u64,
+    share: f64,
+}
+
+#[derive(Serialize)]
+struct StatsView {
+    ledger_canister_id: Principal,
+    symbol: String,
+    games: u64,
+    wins: u64,
+    losses: u64,
+    refunds: u64,
+    forfeits: u64,
+    win_rate: f64,
+    volume: u64,
+    paid_out: u64,
+    outcomes: Vec<OutcomeShare>,
+}
+
+/// The MCP server. The ledger, the clock and the randomness source are injected so the game
+/// can run against mocks in tests; the canister uses the IC ones.
+struct OddEven<L = IcLedger, C = IcClock, R = IcRandomness> {
+    ledger: L,
+    clock: C,
+    randomness: R,
 }
 
-struct OddEven;
+impl OddEven {
+    fn new() -> Self {
+        OddEven {
+            ledger: IcLedger,
+            clock: IcClock,
+            randomness: IcRandomness,
+        }
+    }
+}
 
-impl Handler for OddEven {
+impl<L: Ledger, C: Clock, R: Randomness> Handler for OddEven<L, C, R> {
     async fn call_tool(
         &self,
         context: Context,
@@ -90,82 +410,119 @@ impl Handler for OddEven {
                 let principal = Principal::from_text(subject)
                     .map_err(|_| Error::internal_error("Invalid user principal", None))?;
 
-                if let Some(unfinished_game) = get(principal) {
-                    return Ok(CallToolResult::success(
-                        Content::text(format!("You can not play a new game because you have an unfinished game with game hash = {}", unfinished_game.hash)).into_contents(),
-                    ));
-                }
-
                 let request = from_value::<StartRequest>(Value::Object(req.arguments.ok_or(
                     Error::invalid_params("invalid arguments to tool start", None),
                 )?))
                 .map_err(|_| Error::invalid_params("invalid arguments to tool start", None))?;
 
-                if request.amount < 10_000_000 || request.amount > 500_000_000 {
-                    return Ok(CallToolResult::success(
-                        Content::text("Invalid amount. Amount must be greater than 10_000_000 and less than 500_000_000").into_contents(),
-                    ));
-                }
+                self.start(principal, request).await
+            }
+            "play" => {
+                let subject = context
+                    .subject
+                    .ok_or(Error::internal_error("Invalid user", None))?;
 
-                let random_hex = match get_random_hex().await {
-                    Ok(hex) => hex,
-                    Err(err) => {
-                        eprintln!("Get random hex: {err}");
-                        return Err(Error::internal_error("Internal error", None));
-                    }
-                };
+                let principal = Principal::from_text(subject)
+                    .map_err(|_| Error::internal_error("Invalid user principal", None))?;
 
-                let random_result = match get_game_result().await {
-                    Ok(result) => result,
-                    Err(err) => {
-                        eprintln!("Get random game result: {err}");
-                        return Err(Error::internal_error("Internal error", None));
-                    }
-                };
+                let request = from_value::<PlayRequest>(Value::Object(req.arguments.ok_or(
+                    Error::invalid_params("invalid arguments to tool `play`", None),
+                )?))
+                .map_err(|_| Error::invalid_params("invalid arguments to tool `play`", None))?;
 
-                let timestamp_nanos = time();
+                self.play(principal, request).await
+            }
+            "retry_payout" => {
+                let subject = context
+                    .subject
+                    .ok_or(Error::internal_error("Invalid user", None))?;
 
-                let info = GameInfo {
-                    amount: request.amount,
-                    timestamp_nanos,
-                    random_hex: random_hex.clone(),
-                    result: random_result.to_string(),
-                    hash: get_game_hash(random_result, &random_hex),
-                };
+                let principal = Principal::from_text(subject)
+                    .map_err(|_| Error::internal_error("Invalid user principal", None))?;
 
-                let client = ICRC1Client {
-                    runtime: CdkRuntime,
-                    ledger_canister_id: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai")
-                        .unwrap(),
-                };
+                self.retry_payout(principal).await
+            }
+            "claim_referral_rewards" => {
+                let subject = context
+                    .subject
+                    .ok_or(Error::internal_error("Invalid user", None))?;
 
-                let _ = client
-                    .transfer_from(TransferFromArgs {
-                        spender_subaccount: None,
-                        from: Account {
-                            owner: principal,
-                            subaccount: None,
-                        },
-                        to: Account {
-                            owner: canister_self(),
-                            subaccount: None,
-                        },
-                        amount: Nat::from(request.amount),
-                        fee: None,
-                        memo: None,
-                        created_at_time: None,
+                let principal = Principal::from_text(subject)
+                    .map_err(|_| Error::internal_error("Invalid user principal", None))?;
+
+                self.claim_referral_rewards(principal).await
+            }
+            "list_my_games" => {
+                let subject = context
+                    .subject
+                    .ok_or(Error::internal_error("Invalid user", None))?;
+
+                let principal = Principal::from_text(subject)
+                    .map_err(|_| Error::internal_error("Invalid user principal", None))?;
+
+                let expiry_nanos = get_config().expiry_nanos;
+                let games: Vec<MyGame> = player_games(principal)
+                    .into_iter()
+                    .map(|(game_id, info)| MyGame {
+                        game_id,
+                        game_type: info.game_type(),
+                        ledger_canister_id: info.ledger(),
+                        amount: info.amount,
+                        state: info.state(),
+                        started_at_nanos: info.timestamp_nanos,
+                        expires_at_nanos: info.timestamp_nanos.saturating_add(expiry_nanos),
+                        hash: info.hash,
                     })
-                    .await
-                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?
+                    .collect();
+
+                let content = Content::json(games)
                     .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
 
-                insert(principal, info.clone());
-                Ok(CallToolResult::success(
-                    Content::text(format!("Successfully. Your game hash is {}", info.hash))
-                        .into_contents(),
+                Ok(CallToolResult::success(content.into_contents()))
+            }
+            "get_required_approval" => {
+                let subject = context
+                    .subject
+                    .ok_or(Error::internal_error("Invalid user", None))?;
+
+                let principal = Principal::from_text(subject)
+                    .map_err(|_| Error::internal_error("Invalid user principal", None))?;
+
+                let request = from_value::<GetRequiredApprovalRequest>(Value::Object(
+                    req.arguments.ok_or(Error::invalid_params(
+                        "invalid arguments to tool `get_required_approval`",
+                        None,
+                    ))?,
                 ))
+                .map_err(|_| {
+                    Error::invalid_params("invalid arguments to tool `get_required_approval`", None)
+                })?;
+
+                let ledger = match accepted_token(request.ledger_canister_id.as_deref()) {
+                    Ok((ledger, _)) => ledger,
+                    Err(reason) => {
+                        return Ok(CallToolResult::success(
+                            Content::text(reason).into_contents(),
+                        ))
+                    }
+                };
+
+                let fee = self
+                    .ledger
+                    .fee(ledger)
+                    .await
+                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+                let fee = u128::try_from(fee.0).unwrap_or(u128::MAX);
+
+                let content = Content::json(
+                    self.required_approval(ledger, principal, request.amount, fee)
+                        .await?,
+                )
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+                Ok(CallToolResult::success(content.into_contents()))
             }
-            "play" => {
+            "self_exclude" => {
                 let subject = context
                     .subject
                     .ok_or(Error::internal_error("Invalid user", None))?;
@@ -173,73 +530,109 @@ impl Handler for OddEven {
                 let principal = Principal::from_text(subject)
                     .map_err(|_| Error::internal_error("Invalid user principal", None))?;
 
-                let request = from_value::<PlayRequest>(Value::Object(req.arguments.ok_or(
-                    Error::invalid_params("invalid arguments to tool `play`", None),
+                let request =
+                    from_value::<SelfExcludeRequest>(Value::Object(req.arguments.ok_or(
+                        Error::invalid_params("invalid arguments to tool `self_exclude`", None),
+                    )?))
+                    .map_err(|_| {
+                        Error::invalid_params("invalid arguments to tool `self_exclude`", None)
+                    })?;
+
+                Ok(self.self_exclude(principal, request.days))
+            }
+            "get_game_history" => {
+                let subject = context
+                    .subject
+                    .ok_or(Error::internal_error("Invalid user", None))?;
+
+                let principal = Principal::from_text(subject)
+                    .map_err(|_| Error::internal_error("Invalid user principal", None))?;
+
+                let request = from_value::<GetGameHistoryRequest>(Value::Object(
+                    req.arguments.unwrap_or_default(),
+                ))
+                .map_err(|_| {
+                    Error::invalid_params("invalid arguments to tool `get_game_history`", None)
+                })?;
+
+                let records = player_history(
+                    principal,
+                    request.offset.unwrap_or(0) as usize,
+                    request.limit.unwrap_or(10).min(50) as usize,
+                );
+
+                let content = Content::json(records)
+                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+                Ok(CallToolResult::success(content.into_contents()))
+            }
+            "verify_game" => {
+                let request = from_value::<VerifyGameRequest>(Value::Object(req.arguments.ok_or(
+                    Error::invalid_params("invalid arguments to tool `verify_game`", None),
                 )?))
-                .map_err(|_| Error::invalid_params("invalid arguments to tool `play`", None))?;
+                .map_err(|_| {
+                    Error::invalid_params("invalid arguments to tool `verify_game`", None)
+                })?;
 
-                match get(principal) {
-                    None => Ok(CallToolResult::success(
-                        Content::text(
-                            "You don't have any in-progress game. Start a game using tool `start`.",
-                        )
-                        .into_contents(),
-                    )),
-                    Some(info) => {
-                        let now = time();
-                        if now.saturating_sub(info.timestamp_nanos) > 604_800_000_000_000 {
-                            return Ok(CallToolResult::success(
-                                Content::text(format!(
-                                    "Your game has expired (hash = {}). Contact server's admin to revoke.",
-                                    info.hash
-                                ))
-                                .into_contents(),
-                            ));
-                        }
-
-                        if info.result != request.guess.to_string() {
-                            let plaintext = format!("{}|{}", info.result, info.random_hex);
-                            delete(principal);
-                            return Ok(CallToolResult::success(
-                                Content::text(format!(
-                                    "You lose. Plaintext used for hashing: {plaintext}"
-                                ))
-                                .into_contents(),
-                            ));
-                        }
-
-                        let client = ICRC1Client {
-                            runtime: CdkRuntime,
-                            ledger_canister_id: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai")
-                                .unwrap(),
-                        };
-
-                        let _ = client
-                            .transfer(TransferArg {
-                                to: Account {
-                                    owner: principal,
-                                    subaccount: None,
-                                },
-                                fee: None,
-                                memo: None,
-                                from_subaccount: None,
-                                created_at_time: None,
-                                amount:  Nat::from(2*info.amount),
-                            })
-                            .await
-                            .map_err(|err| Error::internal_error(format!("Could not disburse, please contact server's admin: {err:?}"), None))?
-                            .map_err(|err| Error::internal_error(format!("Could not disburse, please contact server's admin: {err:?}"), None))?;
-
-                        delete(principal);
-                        let plaintext = format!("{}|{}", info.result, info.random_hex);
-                        Ok(CallToolResult::success(
-                            Content::text(format!(
-                                "You win. Plaintext used for hashing: {plaintext}"
-                            ))
-                            .into_contents(),
+                let content = Content::json(verify(
+                    &request.hash,
+                    &request.plaintext,
+                    request.client_seed.as_deref(),
+                ))
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+                Ok(CallToolResult::success(content.into_contents()))
+            }
+            "get_house_stats" => {
+                let request =
+                    from_value::<LedgerRequest>(Value::Object(req.arguments.unwrap_or_default()))
+                        .map_err(|_| {
+                        Error::invalid_params("invalid arguments to tool `get_house_stats`", None)
+                    })?;
+                let ledger = match accepted_token(request.ledger_canister_id.as_deref()) {
+                    Ok((ledger, _)) => ledger,
+                    Err(reason) => {
+                        return Ok(CallToolResult::success(
+                            Content::text(reason).into_contents(),
                         ))
                     }
-                }
+                };
+
+                let content = Content::json(self.house_stats(ledger).await?)
+                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+                Ok(CallToolResult::success(content.into_contents()))
+            }
+            "get_jackpot" => {
+                let request =
+                    from_value::<LedgerRequest>(Value::Object(req.arguments.unwrap_or_default()))
+                        .map_err(|_| {
+                        Error::invalid_params("invalid arguments to tool `get_jackpot`", None)
+                    })?;
+                let ledger = match accepted_token(request.ledger_canister_id.as_deref()) {
+                    Ok((ledger, _)) => ledger,
+                    Err(reason) => {
+                        return Ok(CallToolResult::success(
+                            Content::text(reason).into_contents(),
+                        ))
+                    }
+                };
+
+                let config = get_config();
+                let jackpot = get_jackpot(ledger);
+                let content = Content::json(JackpotInfo {
+                    ledger_canister_id: ledger,
+                    pool: jackpot.pool,
+                    odds: config.jackpot_odds,
+                    contribution_bps: config.jackpot_bps,
+                    total_contributed: jackpot.total_contributed,
+                    total_paid_out: jackpot.total_paid_out,
+                    wins: jackpot.wins,
+                    last_win: jackpot.last_win,
+                })
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+                Ok(CallToolResult::success(content.into_contents()))
             }
             _ => Err(Error::invalid_params("not found tool", None)),
         }
@@ -249,32 +642,204 @@ impl Handler for OddEven {
         _: Context,
         _: Option<PaginatedRequestParam>,
     ) -> Result<ListToolsResult, Error> {
+        let config = get_config();
         Ok(ListToolsResult {
             next_cursor: None,
             tools: vec![
+                Tool {
+                    output_schema: Some(schema_for_type::<StartResult>()),
+                    ..Tool::new(
+                        "start",
+                        format!(
+                            "Start a game. By default the bet is in {symbol}, the default token, on ledger {ledger}. The amount is in {symbol} decimal unit ({decimals} decimals). For example, if you want to bet 1 {symbol}, you must pass in {one} as input. Min amount: {min} ({min_tokens} {symbol}), max amount: {max} ({max_tokens} {symbol}). {other_tokens}Game types: {games}. If game_type is empty, default to `OddEven`. Player limits: {limits}. The bet is pulled with `icrc2_transfer_from`, so approve this canister for the amount plus the ledger fee first (see tool `get_required_approval`). On your first game you can pass the principal of the player who referred you as referrer; they then earn {referral}.{referral_bps:02}% of the house edge of every bet you lose, which is nothing on `OddEven` as it has no house edge. A game expires {expiry} after it starts. This tool returns a short text followed by a JSON result (see the output schema) with the status, the game id, the game hash, the deposit block index and the expiry time. The game hash is hased using SHA256 from the server seed `<GameType>|<Random_Hex>|<Jackpot_Odds>` (`<GameType>|<Random_Hex>` when the jackpot is disabled). To play, call tool `play`.",
+                            symbol = config.token_symbol,
+                            decimals = config.token_decimals,
+                            ledger = config.ledger_canister_id,
+                            one = 10u128.pow(config.token_decimals as u32),
+                            min = config.min_amount,
+                            min_tokens = config.format_amount(config.min_amount),
+                            max = config.max_amount,
+                            max_tokens = config.format_amount(config.max_amount),
+                            expiry = config.format_expiry(),
+                            referral = config.referral_bps / 100,
+                            referral_bps = config.referral_bps % 100,
+                            limits = player_limits_description(&config),
+                            other_tokens = other_tokens_description(&config),
+                            games = GameType::ALL
+                                .iter()
+                                .map(|game_type| format!(
+                                    "`{game_type}` ({}, house edge {}.{:02}%)",
+                                    game_type.game().rules(),
+                                    game_type.game().house_edge_bps() / 100,
+                                    game_type.game().house_edge_bps() % 100
+                                ))
+                                .collect::<Vec<_>>()
+                                .join("; "),
+                        ),
+                        schema_for_type::<StartRequest>(),
+                    )
+                },
+                Tool {
+                    output_schema: Some(schema_for_type::<PlayResult>()),
+                    ..Tool::new(
+                        "play",
+                        "Play a game you started. If game_id is empty, default to your oldest game waiting for a guess. The valid guesses depend on the game type. The result is drawn from `HMAC-SHA256(Random_Hex, client_seed)`, so pass your own client seed to make sure the server could not pick the result. Every game played with a client seed also enters the jackpot draw, which hits when the last 8 bytes of the same HMAC, read as a little-endian integer, are a multiple of the jackpot odds; the jackpot is paid on top of any win. The server seed is revealed afterwards. This tool returns a short text followed by a JSON result (see the output schema) with the status, the result, the payout, the payout block index and the plaintext and client seed to pass to `verify_game`. Make sure you start the game using `start` before playing.",
+                        schema_for_type::<PlayRequest>(),
+                    )
+                },
+                Tool::new(
+                    "retry_payout",
+                    "Retry the payouts of your won or refunded games whose transfer failed. Retries are deduplicated by the ledger, so the payout is never sent twice.",
+                    schema_for_type::<EmptyObject>(),
+                ),
+                Tool::new(
+                    "claim_referral_rewards",
+                    format!(
+                        "Claim your referral rewards. Players who pass your principal as referrer to their first `start` earn you {}.{:02}% of the house edge of every bet they lose (the house edge is 0% for `OddEven`, so only `Dice` and `OverUnder` bets earn rewards). Rewards accrue in the token of the bet and are sent to you on each ledger, minus the ledger fee.",
+                        config.referral_bps / 100,
+                        config.referral_bps % 100
+                    ),
+                    schema_for_type::<EmptyObject>(),
+                ),
+                Tool::new(
+                    "list_my_games",
+                    format!(
+                        "Return your in-progress games, oldest first, with their game id, game type, ledger, amount, game hash, state, start time and expiry time (nanoseconds since the Unix epoch). You can have up to {MAX_OPEN_GAMES_PER_PLAYER} in-progress games."
+                    ),
+                    schema_for_type::<EmptyObject>(),
+                ),
+                Tool::new(
+                    "get_required_approval",
+                    "Return what you must approve before calling `start` with the given amount and ledger (default: the default token): the ledger, the spender account (this canister), the required allowance (amount plus the ledger fee), your current allowance and whether it is sufficient. Call `icrc2_approve` on the ledger with these values if it is not.",
+                    schema_for_type::<GetRequiredApprovalRequest>(),
+                ),
+                Tool::new(
+                    "self_exclude",
+                    format!(
+                        "Block yourself from starting new games for the given number of days. An exclusion can be extended but can not be shortened or lifted. Limits that apply to every player: {}.",
+                        player_limits_description(&config)
+                    ),
+                    schema_for_type::<SelfExcludeRequest>(),
+                ),
+                Tool::new(
+                    "get_game_history",
+                    "Return your finished games, newest first, including the revealed plaintext, your guess, the payout and the ledger block index of the payout. If limit is empty, default to 10 (max 50).",
+                    schema_for_type::<GetGameHistoryRequest>(),
+                ),
+                Tool::new(
+                    "verify_game",
+                    "Verify a finished game. Pass the game hash returned by `start`, the server seed revealed by `play` (`<GameType>|<Random_Hex>|<Jackpot_Odds>`, or `<GameType>|<Random_Hex>` for games not eligible for the jackpot) as plaintext and the client seed you played with. The server recomputes the SHA256 hash, the HMAC-SHA256 result and the jackpot draw, cross-checks them against the recorded game and returns a verdict. For games started before client seeds existed, pass the revealed plaintext (`<GameResult>|<Random_Hex>` or `<GameType>|<GameResult>|<Random_Hex>`) and no client seed.",
+                    schema_for_type::<VerifyGameRequest>(),
+                ),
                 Tool::new(
-                    "start",
-                    "Start a odd-even game. The amount is in ICP decimal unit. For example, if you want to bet 1 ICP, you must pass in 100_000_000 as input. Min amount: 10_000_000 (0.1 ICP), max amount: 500_000_000 (5 ICP). This tool will return the game hash, hased from the template `<GameResult>|<Random_Hex>` using SHA256. To play, call tool `play`.",
-                    schema_for_type::<StartRequest>(),
+                    "get_jackpot",
+                    format!(
+                        "Return the progressive jackpot: the current pool, the odds (a played game hits it with a chance of 1 in `odds`, 0 when disabled), the share of every losing bet on a game with a house edge added to the pool in basis points ({}.{:02}% now), the totals contributed and paid out, the number of wins and the last win. Each token has its own jackpot; if ledger_canister_id is empty, default to the default token. Amounts are in token decimal unit.",
+                        config.jackpot_bps / 100,
+                        config.jackpot_bps % 100
+                    ),
+                    schema_for_type::<LedgerRequest>(),
                 ),
                 Tool::new(
-                    "play",
-                    "Play a odd-even game. Make sure you start the game using `start` before playing.",
-                    schema_for_type::<PlayRequest>(),
+                    "get_house_stats",
+                    "Return the house statistics: canister balance, number of in-progress games, open exposure (payouts owed if every in-progress game is won), jackpot pool, unclaimed referral rewards, bankroll (balance minus open exposure, jackpot pool and unclaimed referral rewards), total wagered and total paid out, for one ledger. Each token has its own bankroll; if ledger_canister_id is empty, default to the default token. Amounts are in token decimal unit.",
+                    schema_for_type::<LedgerRequest>(),
                 ),
             ],
         })
     }
+    async fn list_resources(
+        &self,
+        _: Context,
+        _: Option<PaginatedRequestParam>,
+    ) -> Result<ListResourcesResult, Error> {
+        let default_ledger = get_config().ledger_canister_id;
+        let mut resources = Vec::new();
+        for (ledger, token) in tokens() {
+            // The default token is served at the bare URIs.
+            let suffix = if ledger == default_ledger {
+                String::new()
+            } else {
+                format!("/{ledger}")
+            };
+            resources.push(
+                RawResource {
+                    description: Some(format!(
+                        "Top {LEADERBOARD_SIZE} players of {symbol} games by net profit (payouts minus wagers) with their number of games, wins, win rate and total wagered. Amounts are in {symbol} decimal unit.",
+                        symbol = token.symbol
+                    )),
+                    mime_type: Some("application/json".to_string()),
+                    ..RawResource::new(
+                        format!("{LEADERBOARD_URI}{suffix}"),
+                        format!("{} leaderboard", token.symbol),
+                    )
+                }
+                .no_annotation(),
+            );
+            resources.push(
+                RawResource {
+                    description: Some(format!(
+                        "Statistics over every finished {symbol} game: number of games, wins, losses, refunds and forfeits, win rate, total volume and paid out, and how often each result was drawn per game type. Amounts are in {symbol} decimal unit.",
+                        symbol = token.symbol
+                    )),
+                    mime_type: Some("application/json".to_string()),
+                    ..RawResource::new(
+                        format!("{STATS_URI}{suffix}"),
+                        format!("{} stats", token.symbol),
+                    )
+                }
+                .no_annotation(),
+            );
+        }
+
+        Ok(ListResourcesResult {
+            next_cursor: None,
+            resources,
+        })
+    }
+
+    async fn read_resource(
+        &self,
+        _: Context,
+        req: ReadResourceRequestParam,
+    ) -> Result<ReadResourceResult, Error> {
+        let not_found =
+            || Error::resource_not_found(format!("resource `{}` not found", req.uri), None);
+        // `<base>` reads the default token, `<base>/<ledger>` any accepted token.
+        let (base, ledger) = match req.uri.split_once("://") {
+            Some((scheme, path)) => match path.split_once('/') {
+                Some((name, ledger)) => (format!("{scheme}://{name}"), Some(ledger)),
+                None => (req.uri.clone(), None),
+            },
+            None => return Err(not_found()),
+        };
+        let (ledger, token) = accepted_token(ledger).map_err(|_| not_found())?;
+
+        let text = match base.as_str() {
+            LEADERBOARD_URI => serde_json::to_string(&leaderboard_view(ledger)),
+            STATS_URI => serde_json::to_string(&stats_view(ledger, token)),
+            _ => return Err(not_found()),
+        }
+        .map_err(|err| Error::internal_error(err.to_string(), None))?;
+
+        Ok(ReadResourceResult {
+            contents: vec![ResourceContents::text(text, req.uri)],
+        })
+    }
+
     fn get_info(&self, _: Context) -> ServerInfo {
         ServerInfo {
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .build(),
             server_info: Implementation {
                 name: "Odd-even".to_string(),
                 version: "1.0.0".to_string(),
             },
             instructions: Some(
                 r"
-            This server provides tools for users to play odd-even game. 
+            This server provides tools for users to play odd-even, dice and over/under games. 
             "
                 .to_string(),
             ),
@@ -283,64 +848,2584 @@ impl Handler for OddEven {
     }
 }
 
-#[update]
-fn admin_delete(principal: Principal) -> String {
-    if is_controller(&msg_caller()) {
-        delete(principal);
-        "Successfully".to_string()
-    } else {
-        "Forbidden".to_string()
-    }
-}
+/// The game flow tools, separate from `call_tool` so tests can drive them without an MCP
+/// context.
+impl<L: Ledger, C: Clock, R: Randomness> OddEven<L, C, R> {
+    async fn start(
+        &self,
+        principal: Principal,
+        request: StartRequest,
+    ) -> Result<CallToolResult, Error> {
+        // Games of concurrent calls whose deposit is in flight count as open.
+        if player_games(principal).len() + pending_games(principal) >= MAX_OPEN_GAMES_PER_PLAYER {
+            return StartResult::rejected(format!("You can not start a new game because you have {MAX_OPEN_GAMES_PER_PLAYER} unfinished games. Play them first, see tool `list_my_games`."));
+        }
 
-#[query]
-fn http_request(_: HttpRequest) -> HttpResponse {
-    HttpResponse::builder()
-        .with_status_code(StatusCode::OK)
-        .with_upgrade(true)
-        .build()
-}
+        let config = get_config();
+        let (ledger, token) = match accepted_token(request.ledger_canister_id.as_deref()) {
+            Ok(token) => token,
+            Err(reason) => return StartResult::rejected(reason),
+        };
+        if request.amount < token.min_amount || request.amount > token.max_amount {
+            return StartResult::rejected(format!(
+                "Invalid amount. Amount must be between {} and {}",
+                token.min_amount, token.max_amount
+            ));
+        }
 
-#[update]
-async fn http_request_update(req: HttpRequest<'_>) -> HttpResponse<'_> {
-    OddEven {}
-        .handle_with_oauth(
-            &req,
-            ARGS.with_borrow(|args| OAuthConfig {
-                metadata_url: args.metadata_url.clone(),
-                resource: args.resource.clone(),
-                issuer_configs: IssuerConfig {
-                    issuer: args.issuer.clone(),
-                    jwks_url: args.jwks_url.clone(),
-                    authorization_server: args.authorization_server.clone(),
-                    audience: args.audience.clone(),
-                },
-                scopes_supported: args.scopes.to_vec(),
-            }),
-        )
-        .await
-}
+        let referrer = match request.referrer.as_deref().map(Principal::from_text) {
+            None => None,
+            Some(Ok(referrer)) if referrer != principal => Some(referrer),
+            Some(Ok(_)) => return StartResult::rejected("You can not refer yourself."),
+            Some(Err(_)) => return StartResult::rejected("Invalid referrer principal"),
+        };
 
-async fn get_random_hex() -> Result<String, String> {
-    let bytes = raw_rand().await.map_err(|err| err.to_string())?;
-    Ok(encode(&bytes))
-}
+        let now = self.clock.now();
+        let activity = get_player(principal);
+        if let Err(reason) =
+            check_player_limits(&config, &token, &activity, ledger, request.amount, now)
+        {
+            return StartResult::rejected(reason);
+        }
+        let first_game = activity.last_game_nanos == 0;
+        // Concurrent calls check their limits while this one waits for the ledger, so the
+        // wager and the cooldown count from now on and are given back unless the game starts.
+        let mut pending = PendingStart::new(principal, ledger);
+        pending.reserve_wager(request.amount, now);
 
-async fn get_game_result() -> Result<GameResult, String> {
-    let bytes = raw_rand().await.map_err(|e| e.to_string())?;
-    let parity = bytes.iter().fold(0u8, |acc, &b| acc ^ b) & 1;
-    Ok(if parity == 0 {
-        GameResult::Even
-    } else {
-        GameResult::Odd
-    })
-}
+        let game_type = request.game_type.unwrap_or_default();
+        let game = game_type.game();
 
-fn get_game_hash(result: GameResult, random_hex: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(format!("{result}|{random_hex}"));
-    let result = hasher.finalize();
-    hex::encode(result)
-}
+        let stats = self.house_stats(ledger).await?;
+        let fee = self
+            .ledger
+            .fee(ledger)
+            .await
+            .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+        // Accepting the bet adds the largest possible payout to the exposure while the
+        // deposit only brings in `amount`, so the bankroll must cover the difference
+        // plus the payout fee. Bets of concurrent calls whose deposit is in flight are
+        // not in the open exposure yet, so their share is reserved until the game exists.
+        let fee = i128::try_from(fee.0).unwrap_or(i128::MAX);
+        let max_payout = apply_bps(request.amount, game.max_payout_bps());
+        let bankroll = stats.bankroll - pending_exposure(ledger) as i128;
+        if bankroll < (max_payout - request.amount) as i128 + fee {
+            return StartResult::rejected(format!(
+                "The house can not cover this bet right now. Maximum bet: {}",
+                (bankroll - fee).max(0) * BPS as i128 / (game.max_payout_bps() - BPS) as i128
+            ));
+        }
+        pending.reserve_exposure(max_payout - request.amount);
 
-ic_cdk::export_candid!();
+        let approval = self
+            .required_approval(ledger, principal, request.amount, fee as u128)
+            .await?;
+        if !approval.sufficient {
+            return text_and_json(
+                approval.message.clone(),
+                StartResult {
+                    status: StartStatus::ApprovalRequired,
+                    reason: Some(approval.message),
+                    ledger_canister_id: Some(ledger.to_text()),
+                    amount: Some(request.amount),
+                    required_allowance: Some(approval.required_allowance),
+                    current_allowance: Some(approval.current_allowance),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let random_hex = match self.random_hex().await {
+            Ok(hex) => hex,
+            Err(err) => {
+                eprintln!("Get random hex: {err}");
+                return Err(Error::internal_error("Internal error", None));
+            }
+        };
+
+        let timestamp_nanos = self.clock.now();
+        let jackpot_odds = Some(config.jackpot_odds).filter(|odds| *odds > 0);
+
+        let info = GameInfo {
+            player: Some(principal),
+            amount: request.amount,
+            timestamp_nanos,
+            random_hex: random_hex.clone(),
+            hash: get_game_hash(&game_type.server_seed(&random_hex, jackpot_odds)),
+            result: None,
+            state: Some(GameState::Started),
+            guess: None,
+            game_type: Some(game_type),
+            client_seed: None,
+            jackpot_odds,
+            jackpot_won: None,
+            ledger_canister_id: Some(ledger),
+        };
+
+        let deposit = self
+            .ledger
+            .transfer_from(
+                ledger,
+                TransferFromArgs {
+                    spender_subaccount: None,
+                    from: Account {
+                        owner: principal,
+                        subaccount: None,
+                    },
+                    to: self.ledger.self_account(),
+                    amount: Nat::from(request.amount),
+                    fee: None,
+                    memo: None,
+                    created_at_time: None,
+                },
+            )
+            .await
+            .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+        let deposit_block_index = match deposit {
+            Ok(block_index) => u64::try_from(block_index.0).unwrap_or_default(),
+            Err(err) => return StartResult::rejected(deposit_rejection(err)),
+        };
+
+        let game_id = create(info.clone());
+        pending.commit();
+        add_wagered(ledger, info.amount);
+
+        let referral_note = match referrer {
+            Some(referrer) if first_game && get_referrer(principal).is_none() => {
+                set_referrer(principal, referrer);
+                format!(". {referrer} is now your referrer.")
+            }
+            Some(_) => ". The referrer was ignored because it can only be set on your first game."
+                .to_string(),
+            None => String::new(),
+        };
+        text_and_json(
+            format!(
+                "Successfully. Your game id is {game_id} and your game hash is {}{referral_note}",
+                info.hash
+            ),
+            StartResult {
+                status: StartStatus::Started,
+                game_id: Some(game_id),
+                game_type: Some(game_type),
+                ledger_canister_id: Some(ledger.to_text()),
+                amount: Some(info.amount),
+                hash: Some(info.hash),
+                deposit_block_index: Some(deposit_block_index),
+                started_at_nanos: Some(timestamp_nanos),
+                expires_at_nanos: Some(timestamp_nanos.saturating_add(config.expiry_nanos)),
+                referrer: get_referrer(principal).map(|referrer| referrer.to_text()),
+                ..Default::default()
+            },
+        )
+    }
+
+    async fn play(
+        &self,
+        principal: Principal,
+        request: PlayRequest,
+    ) -> Result<CallToolResult, Error> {
+        let games = player_games(principal);
+        let game = match request.game_id {
+            Some(game_id) => games.into_iter().find(|(id, _)| *id == game_id),
+            None => games
+                .into_iter()
+                .find(|(_, info)| info.state() == GameState::Started),
+        };
+        let Some((game_id, mut info)) = game else {
+            return PlayResult::rejected(
+                "You don't have such an in-progress game. Start a game using tool `start` or list your games using tool `list_my_games`.",
+            );
+        };
+
+        if info.state() != GameState::Started {
+            return PlayResult::rejected(format!(
+                "Your game {game_id} (hash = {}) is already resolved and its payout is pending. Call tool `retry_payout` to receive it.",
+                info.hash
+            ));
+        }
+
+        let config = get_config();
+        let now = self.clock.now();
+        if now.saturating_sub(info.timestamp_nanos) > config.expiry_nanos {
+            let action = match config.expiry_policy {
+                ExpiryPolicy::Refund => "Your bet will be refunded automatically.",
+                ExpiryPolicy::Forfeit => "Your bet is forfeited.",
+            };
+            let reason = format!(
+                "Your game {game_id} has expired (hash = {}). {action}",
+                info.hash
+            );
+            return text_and_json(
+                reason.clone(),
+                PlayResult {
+                    status: PlayStatus::Expired,
+                    reason: Some(reason),
+                    game_id: Some(game_id),
+                    game_type: Some(info.game_type()),
+                    hash: Some(info.hash),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let game_type = info.game_type();
+        let game = game_type.game();
+        if !game.guesses().contains(&request.guess) {
+            return PlayResult::rejected(format!(
+                "Invalid guess for a {game_type} game. Valid guesses: {}",
+                game.guesses().join(", ")
+            ));
+        }
+
+        let (result, jackpot_hit, plaintext, reveal) = match info.result.clone() {
+            Some(result) => {
+                let plaintext = game_type.plaintext(&result, &info.random_hex);
+                let reveal = format!("Plaintext used for hashing: {plaintext}");
+                (result, false, plaintext, reveal)
+            }
+            None => {
+                let client_seed = request.client_seed.unwrap_or_default();
+                let result = game_type.draw_seeded(&info.random_hex, &client_seed);
+                let jackpot_hit = info
+                    .jackpot_odds
+                    .is_some_and(|odds| game::jackpot_hit(&info.random_hex, &client_seed, odds));
+                let server_seed = game_type.server_seed(&info.random_hex, info.jackpot_odds);
+                let reveal = format!(
+                    "Result: {result}. Server seed used for hashing: {server_seed}, client seed: {client_seed:?}"
+                );
+                info.client_seed = Some(client_seed);
+                (result, jackpot_hit, server_seed, reveal)
+            }
+        };
+        let payout_bps = game.payout_bps(&request.guess, &result);
+        info.guess = Some(request.guess);
+        info.result = Some(result);
+
+        // A losing bet feeds the jackpot before the jackpot draw, so a losing winner
+        // takes their own contribution too. Games without a house edge contribute nothing, as
+        // the house would pay the contribution out of its bankroll.
+        if payout_bps == 0 && info.jackpot_odds.is_some() && game.house_edge_bps() > 0 {
+            add_to_jackpot(info.ledger(), apply_bps(info.amount, config.jackpot_bps));
+        }
+        // The referrer earns a share of the house edge, not of the bet, so referrals never
+        // cost the house more than it expects to make.
+        if let Some(referrer) = get_referrer(info.player()).filter(|_| payout_bps == 0) {
+            let reward = apply_bps(
+                apply_bps(info.amount, game.house_edge_bps()),
+                config.referral_bps,
+            );
+            if reward > 0 {
+                add_referral_reward(referrer, info.ledger(), reward);
+            }
+        }
+        let jackpot = if jackpot_hit {
+            let jackpot = take_jackpot(info.ledger(), info.player(), game_id, now);
+            info.jackpot_won = Some(jackpot);
+            jackpot
+        } else {
+            0
+        };
+        let jackpot_note = if jackpot_hit {
+            format!(" You also hit the jackpot and win {jackpot}!")
+        } else {
+            String::new()
+        };
+
+        let payout = apply_bps(info.amount, payout_bps).saturating_add(jackpot);
+        let mut json = PlayResult {
+            status: PlayStatus::Lost,
+            reason: None,
+            game_id: Some(game_id),
+            game_type: Some(game_type),
+            hash: Some(info.hash.clone()),
+            guess: info.guess.clone(),
+            result: info.result.clone(),
+            won: Some(payout_bps > 0),
+            payout: Some(payout),
+            jackpot_won: info.jackpot_won,
+            block_index: None,
+            plaintext: Some(plaintext),
+            client_seed: info.client_seed.clone(),
+        };
+        if payout == 0 {
+            info.state = Some(GameState::Revealed);
+            archive(game_id, info.into_record(game_id, now));
+            return text_and_json(format!("You lose.{jackpot_note} {reveal}"), json);
+        }
+
+        let verdict = if payout_bps == 0 {
+            "You lose"
+        } else {
+            "You win"
+        };
+        info.state = Some(GameState::PayoutPending {
+            amount: payout,
+            created_at_time: now,
+            refund: false,
+        });
+        insert(game_id, info.clone());
+
+        match self.settle_payout(game_id, info).await {
+            Ok(block_index) => {
+                json.status = PlayStatus::Paid;
+                json.block_index = Some(block_index);
+                text_and_json(
+                    format!(
+                        "{verdict}.{jackpot_note} {payout} was sent to you in block {block_index}. {reveal}"
+                    ),
+                    json,
+                )
+            }
+            Err(err) => {
+                json.status = PlayStatus::PayoutPending;
+                json.reason = Some(err.clone());
+                text_and_json(
+                    format!(
+                        "{verdict}.{jackpot_note} The payout of {payout} could not be sent yet ({err}). Call tool `retry_payout` to try again. {reveal}"
+                    ),
+                    json,
+                )
+            }
+        }
+    }
+
+    async fn retry_payout(&self, principal: Principal) -> Result<CallToolResult, Error> {
+        let pending: Vec<(GameId, GameInfo)> = player_games(principal)
+            .into_iter()
+            .filter(|(_, info)| matches!(info.state(), GameState::PayoutPending { .. }))
+            .collect();
+        if pending.is_empty() {
+            return Ok(CallToolResult::success(
+                Content::text("You don't have any pending payout.").into_contents(),
+            ));
+        }
+
+        let mut lines = Vec::new();
+        for (game_id, info) in pending {
+            lines.push(match self.settle_payout(game_id, info).await {
+                Ok(block_index) => format!(
+                    "The payout of game {game_id} was sent in block {block_index}."
+                ),
+                Err(err) => format!(
+                    "The payout of game {game_id} could not be sent yet ({err}). Try again later or contact server's admin."
+                ),
+            });
+        }
+
+        Ok(CallToolResult::success(
+            Content::text(lines.join("\n")).into_contents(),
+        ))
+    }
+
+    fn self_exclude(&self, principal: Principal, days: u32) -> CallToolResult {
+        if days == 0 || days > MAX_SELF_EXCLUSION_DAYS {
+            return CallToolResult::success(
+                Content::text(format!(
+                    "Invalid period. Days must be between 1 and {MAX_SELF_EXCLUSION_DAYS}"
+                ))
+                .into_contents(),
+            );
+        }
+
+        let now = self.clock.now();
+        let mut activity = get_player(principal);
+        // An exclusion can be extended but never shortened.
+        activity.excluded_until_nanos = activity
+            .excluded_until_nanos
+            .max(now.saturating_add(days as u64 * DAY_NANOS));
+        let remaining_days = (activity.excluded_until_nanos - now).div_ceil(DAY_NANOS);
+        set_player(principal, activity);
+
+        CallToolResult::success(
+            Content::text(format!(
+                "Successfully. You can not start new games for the next {remaining_days} day(s). A game you already started can still be played."
+            ))
+            .into_contents(),
+        )
+    }
+
+    async fn claim_referral_rewards(&self, principal: Principal) -> Result<CallToolResult, Error> {
+        let ledgers: Vec<Principal> = referral_rewards(principal)
+            .into_iter()
+            .filter(|(_, rewards)| rewards.owed() > 0)
+            .map(|(ledger, _)| ledger)
+            .collect();
+        if ledgers.is_empty() {
+            return Ok(CallToolResult::success(
+                Content::text(format!(
+                    "You don't have any referral rewards to claim. New players who pass your principal {principal} as `referrer` to their first `start` earn you a share of the house edge of every bet they lose."
+                ))
+                .into_contents(),
+            ));
+        }
+
+        let mut lines = Vec::new();
+        for ledger in ledgers {
+            lines.push(match self.pay_referral_rewards(principal, ledger).await {
+                Ok((amount, block_index)) => {
+                    format!("{amount} was sent to you on ledger {ledger} in block {block_index}.")
+                }
+                Err(err) => format!(
+                    "Your rewards on ledger {ledger} could not be sent ({err}). Try again later."
+                ),
+            });
+        }
+
+        Ok(CallToolResult::success(
+            Content::text(lines.join("\n")).into_contents(),
+        ))
+    }
+}
+
+/// Ends every in-progress game of `principal`, see `OddEven::close_player_games`, and returns
+/// what happened to each.
+#[update]
+async fn admin_delete(principal: Principal) -> String {
+    let caller = msg_caller();
+    if !is_controller(&caller) {
+        return "Forbidden".to_string();
+    }
+
+    let lines = OddEven::new().close_player_games(principal).await;
+    let details = if lines.is_empty() {
+        "No game to delete".to_string()
+    } else {
+        lines.join("\n")
+    };
+    audit(caller, "delete", Some(principal), details.clone());
+    details
+}
+
+/// Reads the default token's symbol and decimals from its ledger, which can not be called
+/// from `init` and `post_upgrade`.
+fn schedule_token_metadata_refresh() {
+    set_timer(Duration::ZERO, || {
+        ic_cdk::futures::spawn(async {
+            let ledger = get_config().ledger_canister_id;
+            match token_metadata(&IcLedger, ledger).await {
+                // The ledger may have been switched during the calls.
+                Ok((symbol, decimals)) if get_config().ledger_canister_id == ledger => {
+                    let mut config = get_config();
+                    config.token_symbol = symbol;
+                    config.token_decimals = decimals;
+                    match config.default_token().validate() {
+                        Ok(()) => set_config(config),
+                        Err(err) => eprintln!("Default token of {ledger}: {err}"),
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("{err}"),
+            }
+        })
+    });
+}
+
+/// Reads the symbol and the decimals of the token of `ledger`.
+async fn token_metadata(ledger: &impl Ledger, id: Principal) -> Result<(String, u8), String> {
+    let decimals = match ledger.decimals(id).await {
+        Ok(decimals) => decimals,
+        Err(err) => return Err(format!("Failed to read the decimals of {id}: {err:?}")),
+    };
+    let symbol = match ledger.symbol(id).await {
+        Ok(symbol) => symbol,
+        Err(err) => return Err(format!("Failed to read the symbol of {id}: {err:?}")),
+    };
+    Ok((symbol, decimals))
+}
+
+/// Applies `update` to the config. When the ledger changes, the symbol and decimals of the new
+/// default token are read from it.
+#[update]
+async fn admin_update_config(update: ConfigUpdate) -> String {
+    let caller = msg_caller();
+    if !is_controller(&caller) {
+        return "Forbidden".to_string();
+    }
+
+    let metadata = match update.ledger_canister_id {
+        Some(ledger) if ledger != get_config().ledger_canister_id => {
+            match token_metadata(&IcLedger, ledger).await {
+                Ok(metadata) => Some(metadata),
+                Err(err) => return err,
+            }
+        }
+        _ => None,
+    };
+
+    // Read after the ledger calls, which a concurrent update may have interleaved with.
+    let current = get_config();
+    let mut config = match current.apply(update) {
+        Ok(config) => config,
+        Err(err) => return err,
+    };
+    if let Some((symbol, decimals)) = metadata {
+        config.token_symbol = symbol;
+        config.token_decimals = decimals;
+        if let Err(err) = config.default_token().validate() {
+            return err;
+        }
+    }
+
+    if config.ledger_canister_id != current.ledger_canister_id {
+        if open_games(current.ledger_canister_id) > 0 {
+            return "Can not change the ledger while there are unfinished games".to_string();
+        }
+        if get_token(config.ledger_canister_id).is_some() {
+            return "The ledger is in the token allow-list. Remove it with `admin_remove_token` first".to_string();
+        }
+    }
+
+    schedule_sweep(config.sweep_interval_secs);
+    audit(caller, "update_config", None, format!("{config:?}"));
+    set_config(config);
+    "Successfully".to_string()
+}
+
+#[derive(CandidType, Default)]
+struct SweepReport {
+    refunded: u64,
+    forfeited: u64,
+    paid: u64,
+    failed: u64,
+}
+
+/// Resolves a stuck payout of game `game_id`. With `block_index`, the payout is marked as sent
+/// in that ledger block, e.g. after checking the ledger manually. Without it, the transfer is
+/// retried with a fresh `created_at_time`; use this only when the ledger has no record of the
+/// previous attempts, as it bypasses deduplication. Payouts past the ledger's transaction window
+/// get a fresh `created_at_time` on their own, see `settle_payout`.
+#[update]
+async fn admin_resolve_payout(game_id: GameId, block_index: Option<u64>) -> String {
+    let caller = msg_caller();
+    if !is_controller(&caller) {
+        return "Forbidden".to_string();
+    }
+
+    let Some(mut info) = get(game_id) else {
+        return "Game not found".to_string();
+    };
+    let GameState::PayoutPending { amount, refund, .. } = info.state() else {
+        return "The game has no pending payout".to_string();
+    };
+
+    if let Some(block_index) = block_index {
+        info.state = Some(if refund {
+            GameState::Refunded {
+                amount,
+                block_index,
+            }
+        } else {
+            GameState::Paid {
+                amount,
+                block_index,
+            }
+        });
+        add_paid_out(info.ledger(), amount);
+        audit(
+            caller,
+            "resolve_payout",
+            Some(info.player()),
+            format!("Marked payout of {amount} for game {game_id} as sent in block {block_index}"),
+        );
+        archive(game_id, info.into_record(game_id, time()));
+        return "Successfully".to_string();
+    }
+
+    info.state = Some(GameState::PayoutPending {
+        amount,
+        created_at_time: time(),
+        refund,
+    });
+    insert(game_id, info.clone());
+
+    let player = info.player();
+    let result = match OddEven::new().settle_payout(game_id, info).await {
+        Ok(block_index) => format!("Successfully. Block index: {block_index}"),
+        Err(err) => err,
+    };
+    audit(
+        caller,
+        "resolve_payout",
+        Some(player),
+        format!("Retried payout of {amount} for game {game_id}: {result}"),
+    );
+    result
+}
+
+#[update]
+async fn admin_sweep_expired() -> Result<SweepReport, String> {
+    let caller = msg_caller();
+    if !is_controller(&caller) {
+        return Err("Forbidden".to_string());
+    }
+
+    let report = OddEven::new()
+        .sweep_expired_games()
+        .await
+        .ok_or("A sweep is already in progress".to_string())?;
+    audit(
+        caller,
+        "sweep_expired",
+        None,
+        format!(
+            "Refunded {}, forfeited {}, paid {}, failed {}",
+            report.refunded, report.forfeited, report.paid, report.failed
+        ),
+    );
+    Ok(report)
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+enum ForceResolution {
+    Refund,
+    Forfeit,
+}
+
+/// Ends a game that waits for a guess, refunding the bet or forfeiting it to the house.
+#[update]
+async fn admin_force_resolve(game_id: GameId, resolution: ForceResolution) -> String {
+    let caller = msg_caller();
+    if !is_controller(&caller) {
+        return "Forbidden".to_string();
+    }
+
+    let Some(mut info) = get(game_id) else {
+        return "Game not found".to_string();
+    };
+    if info.state() != GameState::Started {
+        return "Only a game waiting for a guess can be force-resolved. Use `admin_resolve_payout` for a pending payout.".to_string();
+    }
+
+    let player = info.player();
+    let now = time();
+    let result = match resolution {
+        ForceResolution::Forfeit => {
+            archive(game_id, info.into_record(game_id, now));
+            "Successfully".to_string()
+        }
+        ForceResolution::Refund => {
+            info.state = Some(GameState::PayoutPending {
+                amount: info.amount,
+                created_at_time: now,
+                refund: true,
+            });
+            insert(game_id, info.clone());
+
+            match OddEven::new().settle_payout(game_id, info).await {
+                Ok(block_index) => format!("Successfully. Block index: {block_index}"),
+                Err(err) => {
+                    format!("The refund is pending and will be retried by the sweep: {err}")
+                }
+            }
+        }
+    };
+
+    audit(
+        caller,
+        "force_resolve",
+        Some(player),
+        format!("{resolution:?} game {game_id}: {result}"),
+    );
+    result
+}
+
+#[derive(CandidType, Deserialize, Default)]
+struct GameFilter {
+    player: Option<Principal>,
+    min_amount: Option<u64>,
+    max_amount: Option<u64>,
+    min_age_nanos: Option<u64>,
+    max_age_nanos: Option<u64>,
+    expired_only: Option<bool>,
+}
+
+impl GameFilter {
+    fn matches(&self, info: &GameInfo, now: u64, expiry_nanos: u64) -> bool {
+        let age_nanos = now.saturating_sub(info.timestamp_nanos);
+        self.player.is_none_or(|player| info.player == Some(player))
+            && self.min_amount.is_none_or(|min| info.amount >= min)
+            && self.max_amount.is_none_or(|max| info.amount <= max)
+            && self.min_age_nanos.is_none_or(|min| age_nanos >= min)
+            && self.max_age_nanos.is_none_or(|max| age_nanos <= max)
+            && (!self.expired_only.unwrap_or(false) || is_expired(info, now, expiry_nanos))
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+enum GameLookup {
+    Id(GameId),
+    Hash(String),
+}
+
+#[derive(CandidType)]
+struct OpenGame {
+    game_id: GameId,
+    player: Principal,
+    game: GameInfo,
+    age_nanos: u64,
+    expired: bool,
+}
+
+impl OpenGame {
+    fn new(game_id: GameId, game: GameInfo, now: u64, expiry_nanos: u64) -> Self {
+        OpenGame {
+            game_id,
+            player: game.player(),
+            age_nanos: now.saturating_sub(game.timestamp_nanos),
+            expired: is_expired(&game, now, expiry_nanos),
+            game,
+        }
+    }
+}
+
+/// Lists in-progress games matching `filter`, oldest first, at most `MAX_ADMIN_PAGE_SIZE` per call.
+#[query]
+fn admin_list_games(filter: GameFilter, offset: u64, limit: u64) -> Result<Vec<OpenGame>, String> {
+    if !is_controller(&msg_caller()) {
+        return Err("Forbidden".to_string());
+    }
+
+    let now = time();
+    let expiry_nanos = get_config().expiry_nanos;
+    Ok(list_games(
+        |info| filter.matches(info, now, expiry_nanos),
+        offset as usize,
+        limit.min(MAX_ADMIN_PAGE_SIZE) as usize,
+    )
+    .into_iter()
+    .map(|(game_id, game)| OpenGame::new(game_id, game, now, expiry_nanos))
+    .collect())
+}
+
+#[query]
+fn admin_get_game(lookup: GameLookup) -> Result<Option<OpenGame>, String> {
+    if !is_controller(&msg_caller()) {
+        return Err("Forbidden".to_string());
+    }
+
+    let game = match lookup {
+        GameLookup::Id(game_id) => get(game_id).map(|game| (game_id, game)),
+        GameLookup::Hash(hash) => find_game(&hash),
+    };
+    let now = time();
+    let expiry_nanos = get_config().expiry_nanos;
+    Ok(game.map(|(game_id, game)| OpenGame::new(game_id, game, now, expiry_nanos)))
+}
+
+/// Returns audit log entries, newest first.
+#[query]
+fn admin_get_audit_log(offset: u64, limit: u64) -> Result<Vec<AuditEntry>, String> {
+    if is_controller(&msg_caller()) {
+        Ok(audit_log(
+            offset as usize,
+            limit.min(MAX_ADMIN_PAGE_SIZE) as usize,
+        ))
+    } else {
+        Err("Forbidden".to_string())
+    }
+}
+
+#[query]
+fn get_game_config() -> Config {
+    get_config()
+}
+
+/// Returns every token bets are accepted in, the default token first.
+#[query]
+fn get_tokens() -> Vec<(Principal, TokenConfig)> {
+    tokens()
+}
+
+/// Accepts bets on `ledger` between `min_amount` and `max_amount` base units, with the given
+/// wager caps per player, or updates the limits of an accepted token. The symbol and decimals
+/// are read from the ledger.
+#[update]
+async fn admin_add_token(
+    ledger: Principal,
+    min_amount: u64,
+    max_amount: u64,
+    daily_wager_limit: u64,
+    weekly_wager_limit: u64,
+) -> String {
+    let caller = msg_caller();
+    if !is_controller(&caller) {
+        return "Forbidden".to_string();
+    }
+
+    if ledger == get_config().ledger_canister_id {
+        return "The default token is configured with `admin_update_config`".to_string();
+    }
+
+    let (symbol, decimals) = match token_metadata(&IcLedger, ledger).await {
+        Ok(metadata) => metadata,
+        Err(err) => return err,
+    };
+
+    let token = TokenConfig {
+        symbol,
+        decimals,
+        min_amount,
+        max_amount,
+        daily_wager_limit,
+        weekly_wager_limit,
+    };
+    if let Err(err) = token.validate() {
+        return err;
+    }
+
+    audit(caller, "add_token", None, format!("{ledger}: {token:?}"));
+    set_token(ledger, token);
+    "Successfully".to_string()
+}
+
+/// Stops accepting bets on `ledger`. Refused while games on it are in progress.
+#[update]
+fn admin_remove_token(ledger: Principal) -> String {
+    let caller = msg_caller();
+    if !is_controller(&caller) {
+        return "Forbidden".to_string();
+    }
+
+    if open_games(ledger) > 0 {
+        return "Can not remove a token while there are unfinished games on its ledger".to_string();
+    }
+
+    match remove_token(ledger) {
+        Some(token) => {
+            audit(caller, "remove_token", None, format!("{ledger}: {token:?}"));
+            "Successfully".to_string()
+        }
+        None => "Token not found".to_string(),
+    }
+}
+
+/// Exports finished games grouped by player, each player's games in the order they finished.
+/// Returns at most `MAX_ADMIN_PAGE_SIZE` records per call.
+#[query]
+fn admin_export_history(offset: u64, limit: u64) -> Result<Vec<GameRecord>, String> {
+    if is_controller(&msg_caller()) {
+        Ok(history(
+            offset as usize,
+            limit.min(MAX_ADMIN_PAGE_SIZE) as usize,
+        ))
+    } else {
+        Err("Forbidden".to_string())
+    }
+}
+
+#[query]
+fn verify_game(hash: String, plaintext: String, client_seed: Option<String>) -> GameVerification {
+    verify(&hash, &plaintext, client_seed.as_deref())
+}
+
+#[query]
+fn http_request(_: HttpRequest) -> HttpResponse {
+    HttpResponse::builder()
+        .with_status_code(StatusCode::OK)
+        .with_upgrade(true)
+        .build()
+}
+
+#[update]
+async fn http_request_update(req: HttpRequest<'_>) -> HttpResponse<'_> {
+    OddEven::new()
+        .handle_with_oauth(
+            &req,
+            ARGS.with_borrow(|args| OAuthConfig {
+                metadata_url: args.metadata_url.clone(),
+                resource: args.resource.clone(),
+                issuer_configs: IssuerConfig {
+                    issuer: args.issuer.clone(),
+                    jwks_url: args.jwks_url.clone(),
+                    authorization_server: args.authorization_server.clone(),
+                    audience: args.audience.clone(),
+                },
+                scopes_supported: args.scopes.to_vec(),
+            }),
+        )
+        .await
+}
+
+fn audit(caller: Principal, action: &str, player: Option<Principal>, details: String) {
+    append_audit(AuditEntry {
+        timestamp_nanos: time(),
+        caller,
+        action: action.to_string(),
+        player,
+        details,
+    });
+}
+
+/// A game waiting for a guess past `expiry_nanos` can no longer be played.
+fn is_expired(info: &GameInfo, now: u64, expiry_nanos: u64) -> bool {
+    info.state() == GameState::Started && now.saturating_sub(info.timestamp_nanos) > expiry_nanos
+}
+
+/// Returns why a player with `activity` may not start a game of `amount` of `token`, kept by
+/// `ledger`, at `now`.
+fn check_player_limits(
+    config: &Config,
+    token: &TokenConfig,
+    activity: &PlayerActivity,
+    ledger: Principal,
+    amount: u64,
+    now: u64,
+) -> Result<(), String> {
+    if now < activity.excluded_until_nanos {
+        return Err(format!(
+            "You excluded yourself from playing. The exclusion ends in {} day(s).",
+            (activity.excluded_until_nanos - now).div_ceil(DAY_NANOS)
+        ));
+    }
+
+    let next_game_nanos = activity
+        .last_game_nanos
+        .saturating_add(config.cooldown_secs.saturating_mul(1_000_000_000));
+    if now < next_game_nanos {
+        return Err(format!(
+            "Please wait {} second(s) before starting a new game.",
+            (next_game_nanos - now).div_ceil(1_000_000_000)
+        ));
+    }
+
+    // Each token has its own caps, in its base units.
+    let (wagered_day, wagered_week) = activity.wagered(ledger, now);
+    if token.daily_wager_limit > 0 && wagered_day.saturating_add(amount) > token.daily_wager_limit {
+        return Err(format!(
+            "This bet exceeds your daily wager limit of {} {}. You can wager {} more today.",
+            token.daily_wager_limit,
+            token.symbol,
+            token.daily_wager_limit.saturating_sub(wagered_day)
+        ));
+    }
+
+    if token.weekly_wager_limit > 0
+        && wagered_week.saturating_add(amount) > token.weekly_wager_limit
+    {
+        return Err(format!(
+            "This bet exceeds your weekly wager limit of {} {}. You can wager {} more this week.",
+            token.weekly_wager_limit,
+            token.symbol,
+            token.weekly_wager_limit.saturating_sub(wagered_week)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Explains to the player why the ledger rejected the deposit of a bet.
+fn deposit_rejection(err: TransferFromError) -> String {
+    match err {
+        // The allowance or balance may have changed since the pre-check.
+        TransferFromError::InsufficientAllowance { allowance } => format!(
+            "The deposit was rejected because your allowance of {allowance} does not cover the bet plus the ledger fee. Call tool `get_required_approval` to get the amount to approve."
+        ),
+        TransferFromError::InsufficientFunds { balance } => format!(
+            "The deposit was rejected because your balance of {balance} does not cover the bet plus the ledger fee."
+        ),
+        TransferFromError::BadFee { expected_fee } => format!(
+            "The deposit was rejected because the ledger fee changed to {expected_fee}. Call tool `get_required_approval` to check your allowance, then try again."
+        ),
+        TransferFromError::BadBurn { min_burn_amount } => format!(
+            "The deposit was rejected as a burn below the minimum of {min_burn_amount}. Bets can not be placed from the minting account of the ledger."
+        ),
+        TransferFromError::TooOld | TransferFromError::CreatedInFuture { .. } => {
+            "The deposit was rejected because the clocks of the ledger and the game disagree. Try again in a moment.".to_string()
+        }
+        TransferFromError::Duplicate { duplicate_of } => format!(
+            "The deposit was rejected as a duplicate of ledger block {duplicate_of}, so no game was started. Contact the server's admin if that block charged you."
+        ),
+        TransferFromError::TemporarilyUnavailable => {
+            "The deposit was rejected because the ledger is temporarily unavailable. Try again later.".to_string()
+        }
+        TransferFromError::GenericError {
+            error_code,
+            message,
+        } => format!("The deposit was rejected by the ledger (error {error_code}): {message}"),
+    }
+}
+
+fn other_tokens_description(config: &Config) -> String {
+    let others: Vec<String> = tokens()
+        .into_iter()
+        .filter(|(ledger, _)| *ledger != config.ledger_canister_id)
+        .map(|(ledger, token)| {
+            format!(
+                "{symbol} on ledger {ledger} ({decimals} decimals, min amount {min} ({min_tokens} {symbol}), max amount {max} ({max_tokens} {symbol}), daily wager cap {daily}, weekly wager cap {weekly})",
+                symbol = token.symbol,
+                decimals = token.decimals,
+                min = token.min_amount,
+                min_tokens = token.format_amount(token.min_amount),
+                max = token.max_amount,
+                max_tokens = token.format_amount(token.max_amount),
+                daily = wager_cap(&token, token.daily_wager_limit),
+                weekly = wager_cap(&token, token.weekly_wager_limit),
+            )
+        })
+        .collect();
+    if others.is_empty() {
+        return String::new();
+    }
+
+    format!(
+        "To bet in another token, pass its ledger as ledger_canister_id; the amount is then in that token's decimal unit. Other tokens: {}. ",
+        others.join("; ")
+    )
+}
+
+/// Formats a wager cap of `token`, in base units and in decimal units.
+fn wager_cap(token: &TokenConfig, limit: u64) -> String {
+    if limit == 0 {
+        "none".to_string()
+    } else {
+        format!("{limit} ({} {})", token.format_amount(limit), token.symbol)
+    }
+}
+
+fn player_limits_description(config: &Config) -> String {
+    let token = config.default_token();
+    format!(
+        "daily wager cap {}, weekly wager cap {} (bets in other tokens count towards the caps of their token), {} second(s) between games",
+        wager_cap(&token, config.daily_wager_limit),
+        wager_cap(&token, config.weekly_wager_limit),
+        config.cooldown_secs
+    )
+}
+
+/// Ledger, clock and randomness dependent helpers shared by the tools, the admin endpoints and
+/// the sweep.
+impl<L: Ledger, C: Clock, R: Randomness> OddEven<L, C, R> {
+    /// Compares the allowance `player` gave this canister with what a bet of `amount` needs.
+    /// `transfer_from` charges the ledger fee to the player on top of the amount, so the allowance
+    /// must cover both.
+    async fn required_approval(
+        &self,
+        ledger: Principal,
+        player: Principal,
+        amount: u64,
+        fee: u128,
+    ) -> Result<RequiredApproval, Error> {
+        let spender = self.ledger.self_account();
+
+        let allowance = self
+            .ledger
+            .allowance(
+                ledger,
+                AllowanceArgs {
+                    account: Account {
+                        owner: player,
+                        subaccount: None,
+                    },
+                    spender,
+                },
+            )
+            .await
+            .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+        let required_allowance = (amount as u128).saturating_add(fee);
+        let current_allowance = u128::try_from(allowance.allowance.0).unwrap_or(u128::MAX);
+        let sufficient = current_allowance >= required_allowance;
+        let message = if sufficient {
+            "Your allowance covers this bet.".to_string()
+        } else {
+            format!(
+                "Your allowance of {current_allowance} does not cover this bet. Call `icrc2_approve` on ledger {} with spender {} and amount {required_allowance} (the bet of {amount} plus the ledger fee of {fee}), then call `start` again.",
+                ledger, spender.owner
+            )
+        };
+
+        Ok(RequiredApproval {
+            ledger_canister_id: ledger,
+            spender,
+            amount,
+            fee,
+            required_allowance,
+            current_allowance,
+            expires_at: allowance.expires_at,
+            sufficient,
+            message,
+        })
+    }
+
+    /// Sends the pending payout of `info` and archives the game once the ledger confirms it.
+    /// Every attempt reuses the recorded `created_at_time` and the game hash as memo, so a retry
+    /// of a transfer that already went through is rejected by the ledger as a duplicate instead
+    /// of paying twice.
+    ///
+    /// The ledger only deduplicates within its transaction window and rejects older transfers
+    /// as `TooOld`. Past the window it can no longer tell whether an attempt went through, so
+    /// the payout is retried once with a fresh `created_at_time`, which later attempts reuse.
+    /// An attempt that finds the stored time already refreshed by a concurrent one sends with
+    /// that time, so the ledger deduplicates the two. Retries within the window, such as those
+    /// of the sweep, settle an attempt that went through long before that.
+    async fn settle_payout(&self, game_id: GameId, mut info: GameInfo) -> Result<u64, String> {
+        let GameState::PayoutPending {
+            amount,
+            created_at_time,
+            refund,
+        } = info.state()
+        else {
+            return Err("The game has no pending payout".to_string());
+        };
+
+        let mut result = self.send_payout(&info, amount, created_at_time).await;
+        if matches!(result, Ok(Err(TransferError::TooOld))) {
+            // A concurrent attempt may have settled the game or refreshed its time while this
+            // transfer was in flight.
+            let Some(stored) = get(game_id) else {
+                return Err("The payout was settled by another attempt".to_string());
+            };
+            let GameState::PayoutPending {
+                created_at_time: stored_time,
+                ..
+            } = stored.state()
+            else {
+                return Err("The payout was settled by another attempt".to_string());
+            };
+            info = stored;
+            let created_at_time = if stored_time > created_at_time {
+                stored_time
+            } else {
+                let now = self.clock.now();
+                info.state = Some(GameState::PayoutPending {
+                    amount,
+                    created_at_time: now,
+                    refund,
+                });
+                insert(game_id, info.clone());
+                now
+            };
+            result = self.send_payout(&info, amount, created_at_time).await;
+        }
+
+        let block_index = match result {
+            Ok(Ok(block_index))
+            | Ok(Err(TransferError::Duplicate {
+                duplicate_of: block_index,
+            })) => u64::try_from(block_index.0).unwrap_or_default(),
+            Ok(Err(err)) => return Err(format!("{err:?}")),
+            Err(err) => return Err(format!("{err:?}")),
+        };
+
+        // A concurrent attempt may have settled the game while this transfer was in flight.
+        if get(game_id).is_none() {
+            return Ok(block_index);
+        }
+
+        info.state = Some(if refund {
+            GameState::Refunded {
+                amount,
+                block_index,
+            }
+        } else {
+            GameState::Paid {
+                amount,
+                block_index,
+            }
+        });
+        add_paid_out(info.ledger(), amount);
+        archive(game_id, info.into_record(game_id, self.clock.now()));
+        Ok(block_index)
+    }
+
+    async fn send_payout(
+        &self,
+        info: &GameInfo,
+        amount: u64,
+        created_at_time: u64,
+    ) -> CallResult<Result<Nat, TransferError>> {
+        self.ledger
+            .transfer(
+                info.ledger(),
+                TransferArg {
+                    to: Account {
+                        owner: info.player(),
+                        subaccount: None,
+                    },
+                    fee: None,
+                    memo: Some(Memo::from(hex::decode(&info.hash).unwrap_or_default())),
+                    from_subaccount: None,
+                    created_at_time: Some(created_at_time),
+                    amount: Nat::from(amount),
+                },
+            )
+            .await
+    }
+
+    async fn house_stats(&self, ledger: Principal) -> Result<HouseStats, Error> {
+        let balance = self
+            .ledger
+            .balance_of(ledger, self.ledger.self_account())
+            .await
+            .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+        let balance = u128::try_from(balance.0).unwrap_or(u128::MAX);
+
+        let open_exposure = open_exposure(ledger);
+        let jackpot = get_jackpot(ledger).pool;
+        let referral_rewards = referral_rewards_owed(ledger);
+        let totals = get_totals(ledger);
+        Ok(HouseStats {
+            ledger_canister_id: ledger,
+            balance,
+            open_games: open_games(ledger),
+            open_exposure,
+            jackpot,
+            referral_rewards,
+            bankroll: i128::try_from(balance).unwrap_or(i128::MAX)
+                - open_exposure as i128
+                - jackpot as i128
+                - referral_rewards as i128,
+            total_wagered: totals.total_wagered,
+            total_paid_out: totals.total_paid_out,
+        })
+    }
+
+    /// Refunds or forfeits every expired game according to the configured policy.
+    /// Returns `None` if another sweep is still running.
+    async fn sweep_expired_games(&self) -> Option<SweepReport> {
+        let _guard = SweepGuard::acquire()?;
+
+        let config = get_config();
+        let mut report = SweepReport::default();
+        let now = self.clock.now();
+        for (game_id, mut info) in expired_games(now, config.expiry_nanos) {
+            match config.expiry_policy {
+                ExpiryPolicy::Forfeit => {
+                    archive(game_id, info.into_record(game_id, now));
+                    report.forfeited += 1;
+                }
+                ExpiryPolicy::Refund => {
+                    info.state = Some(GameState::PayoutPending {
+                        amount: info.amount,
+                        created_at_time: now,
+                        refund: true,
+                    });
+                    insert(game_id, info);
+                }
+            }
+        }
+
+        // Settles the refunds queued above together with payouts left over from failed attempts.
+        for (game_id, info) in pending_payouts() {
+            let refund = matches!(info.state(), GameState::PayoutPending { refund: true, .. });
+            match self.settle_payout(game_id, info).await {
+                Ok(_) if refund => report.refunded += 1,
+                Ok(_) => report.paid += 1,
+                Err(err) => {
+                    eprintln!("Settle payout of game {game_id}: {err}");
+                    report.failed += 1;
+                }
+            }
+        }
+
+        Some(report)
+    }
+
+    /// Sends the rewards of `referrer` on `ledger`, net of the ledger fee. The claim is stored
+    /// before the transfer and resent as is until the ledger settles it, so a transfer whose
+    /// reply was lost is deduplicated rather than paid twice. The rewards go back to the
+    /// referrer only when the ledger rejects the transfer and no other attempt is sending the
+    /// claim, as that one may go through. A claim older than the transaction window is resent
+    /// once with a fresh `created_at_time`, as `settle_payout` does, unless a concurrent attempt
+    /// already refreshed it.
+    async fn pay_referral_rewards(
+        &self,
+        referrer: Principal,
+        ledger: Principal,
+    ) -> Result<(u64, u64), String> {
+        let fee = self
+            .ledger
+            .fee(ledger)
+            .await
+            .map_err(|err| format!("{err:?}"))?;
+        let fee = u64::try_from(fee.0).unwrap_or(u64::MAX);
+
+        let mut claim = open_referral_claim(referrer, ledger, fee, self.clock.now())
+            .map_err(|amount| format!("{amount} does not cover the ledger fee of {fee}"))?;
+        let _attempt = ClaimAttempt::new(referrer, ledger);
+        let mut refreshed = false;
+        loop {
+            let result = self
+                .ledger
+                .transfer(
+                    ledger,
+                    TransferArg {
+                        to: Account {
+                            owner: referrer,
+                            subaccount: None,
+                        },
+                        fee: None,
+                        memo: Some(Memo::from(claim.id.to_be_bytes().to_vec())),
+                        from_subaccount: None,
+                        created_at_time: Some(claim.created_at_time),
+                        amount: Nat::from(claim.amount - claim.fee),
+                    },
+                )
+                .await;
+
+            // A concurrent attempt may have settled the claim or refreshed its time meanwhile.
+            let stored = pending_referral_claim(referrer, ledger).filter(|c| c.id == claim.id);
+            let block_index = match result {
+                Ok(Ok(block_index)) => block_index,
+                Ok(Err(TransferError::Duplicate { duplicate_of })) => duplicate_of,
+                Ok(Err(TransferError::TooOld)) if !refreshed => {
+                    let Some(stored) = stored else {
+                        return Err("the claim was settled by another attempt".to_string());
+                    };
+                    refreshed = true;
+                    if stored.created_at_time > claim.created_at_time {
+                        claim = stored;
+                    } else {
+                        claim.created_at_time = self.clock.now();
+                        refresh_referral_claim(referrer, ledger, claim.created_at_time);
+                    }
+                    continue;
+                }
+                Ok(Err(err)) => {
+                    let unchanged =
+                        stored.is_some_and(|c| c.created_at_time == claim.created_at_time);
+                    if unchanged && claim_attempts(referrer, ledger) == 1 {
+                        close_referral_claim(referrer, ledger, claim.id, false);
+                    }
+                    return Err(format!("{err:?}"));
+                }
+                // The transfer may have gone through; the next claim resends it.
+                Err(err) => return Err(format!("{err:?}")),
+            };
+            close_referral_claim(referrer, ledger, claim.id, true);
+            return Ok((
+                claim.amount - claim.fee,
+                u64::try_from(block_index.0).unwrap_or_default(),
+            ));
+        }
+    }
+
+    /// Ends every in-progress game of `player` without losing their funds: a bet waiting for a
+    /// guess is refunded and a pending payout is sent. Each game moves to the history once the
+    /// ledger confirms its transfer; a game whose transfer fails stays pending for the sweep.
+    /// Returns a line per game with its hash, the amount and the result.
+    async fn close_player_games(&self, player: Principal) -> Vec<String> {
+        let now = self.clock.now();
+        let mut lines = Vec::new();
+        for (game_id, mut info) in player_games(player) {
+            if info.state() == GameState::Started {
+                info.state = Some(GameState::PayoutPending {
+                    amount: info.amount,
+                    created_at_time: now,
+                    refund: true,
+                });
+                insert(game_id, info.clone());
+            }
+
+            let hash = info.hash.clone();
+            let GameState::PayoutPending { amount, refund, .. } = info.state() else {
+                // Nothing is owed on a game that was resolved but not archived.
+                archive(game_id, info.into_record(game_id, now));
+                lines.push(format!(
+                    "Game {game_id} (hash {hash}) archived, nothing owed"
+                ));
+                continue;
+            };
+            let kind = if refund { "refund" } else { "payout" };
+            lines.push(match self.settle_payout(game_id, info).await {
+                Ok(block_index) => format!(
+                    "Game {game_id} (hash {hash}): {kind} of {amount} sent in block {block_index}"
+                ),
+                Err(err) => format!(
+                    "Game {game_id} (hash {hash}): {kind} of {amount} pending, to be retried by the sweep ({err})"
+                ),
+            });
+        }
+        lines
+    }
+
+    /// Returns a fresh 32-byte random draw as hex, the `Random_Hex` of a game.
+    async fn random_hex(&self) -> Result<String, String> {
+        let bytes = self.randomness.random_bytes().await?;
+        Ok(encode(&bytes))
+    }
+}
+
+fn leaderboard_view(ledger: Principal) -> Vec<LeaderboardEntry> {
+    leaderboard(ledger, LEADERBOARD_SIZE)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (player, stats))| LeaderboardEntry {
+            rank: i + 1,
+            player,
+            net_profit: stats.net_profit(),
+            games: stats.games,
+            wins: stats.wins,
+            win_rate: stats.win_rate(),
+            wagered: stats.wagered,
+        })
+        .collect()
+}
+
+fn stats_view(ledger: Principal, token: TokenConfig) -> StatsView {
+    let stats = get_stats(ledger);
+    let outcomes = stats
+        .outcomes
+        .iter()
+        .map(|o| {
+            let played: u64 = stats
+                .outcomes
+                .iter()
+                .filter(|other| other.game_type == o.game_type)
+                .map(|other| other.count)
+                .sum();
+            OutcomeShare {
+                game_type: o.game_type,
+                result: o.result.clone(),
+                count: o.count,
+                share: o.count as f64 / played as f64,
+            }
+        })
+        .collect();
+
+    StatsView {
+        ledger_canister_id: ledger,
+        symbol: token.symbol,
+        games: stats.games,
+        wins: stats.wins,
+        losses: stats.losses,
+        refunds: stats.refunds,
+        forfeits: stats.forfeits,
+        win_rate: if stats.wins + stats.losses == 0 {
+            0.0
+        } else {
+            stats.wins as f64 / (stats.wins + stats.losses) as f64
+        },
+        volume: stats.volume,
+        paid_out: stats.paid_out,
+        outcomes,
+    }
+}
+
+/// Resolves the ledger id passed to a tool, the default ledger if empty, to an accepted token.
+fn accepted_token(ledger: Option<&str>) -> Result<(Principal, TokenConfig), String> {
+    let ledger = match ledger {
+        Some(ledger) => Principal::from_text(ledger)
+            .map_err(|_| format!("Invalid ledger canister id: {ledger}"))?,
+        None => get_config().ledger_canister_id,
+    };
+    get_token(ledger)
+        .map(|token| (ledger, token))
+        .ok_or_else(|| {
+            format!(
+                "Bets are not accepted on ledger {ledger}. Accepted ledgers: {}",
+                tokens()
+                    .iter()
+                    .map(|(ledger, token)| format!("{ledger} ({})", token.symbol))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+fn schedule_sweep(interval_secs: u64) {
+    if let Some(timer) = SWEEP_TIMER.take() {
+        clear_timer(timer);
+    }
+
+    if interval_secs > 0 {
+        let timer = set_timer_interval(Duration::from_secs(interval_secs), || {
+            ic_cdk::futures::spawn(async {
+                if let Some(report) = OddEven::new().sweep_expired_games().await {
+                    if report.failed > 0 {
+                        eprintln!("Sweep expired games: {} refund(s) failed", report.failed);
+                    }
+                }
+            })
+        });
+        SWEEP_TIMER.set(Some(timer));
+    }
+}
+
+/// Counts the history into the statistics in batches, one per timer call, so a long history
+/// does not exceed the instruction limit of `post_upgrade`.
+fn schedule_stats_rebuild() {
+    set_timer(Duration::ZERO, || {
+        if rebuild_stats_batch(STATS_REBUILD_BATCH) {
+            schedule_stats_rebuild();
+        }
+    });
+}
+
+/// Marks a sweep as running until dropped.
+struct SweepGuard;
+
+impl SweepGuard {
+    fn acquire() -> Option<Self> {
+        if SWEEPING.replace(true) {
+            None
+        } else {
+            Some(SweepGuard)
+        }
+    }
+}
+
+impl Drop for SweepGuard {
+    fn drop(&mut self) {
+        SWEEPING.set(false);
+    }
+}
+
+/// A bet `start` accepted while its deposit is in flight. The open games and bankroll checks
+/// of other calls count its game slot and reserved exposure until it is dropped, by which time
+/// the game holds them. The player's wager is recorded right away and taken back on drop unless
+/// the game was stored.
+struct PendingStart {
+    player: Principal,
+    ledger: Principal,
+    exposure: u64,
+    /// Amount and time of the recorded wager, and the start of the player's previous game.
+    wager: Option<(u64, u64, u64)>,
+}
+
+impl PendingStart {
+    fn new(player: Principal, ledger: Principal) -> Self {
+        PENDING_GAMES.with_borrow_mut(|p| *p.entry(player).or_default() += 1);
+        PendingStart {
+            player,
+            ledger,
+            exposure: 0,
+            wager: None,
+        }
+    }
+
+    fn reserve_wager(&mut self, amount: u64, now: u64) {
+        let mut activity = get_player(self.player);
+        self.wager = Some((amount, now, activity.last_game_nanos));
+        activity.record_wager(self.ledger, amount, now);
+        set_player(self.player, activity);
+    }
+
+    fn reserve_exposure(&mut self, exposure: u64) {
+        PENDING_EXPOSURE.with_borrow_mut(|p| *p.entry(self.ledger).or_default() += exposure);
+        self.exposure += exposure;
+    }
+
+    /// Keeps the wager once the game is stored.
+    fn commit(&mut self) {
+        self.wager = None;
+    }
+}
+
+impl Drop for PendingStart {
+    fn drop(&mut self) {
+        PENDING_GAMES.with_borrow_mut(|p| {
+            if let Some(games) = p.get_mut(&self.player) {
+                *games -= 1;
+                if *games == 0 {
+                    p.remove(&self.player);
+                }
+            }
+        });
+        if let Some((amount, at, previous_game_nanos)) = self.wager.take() {
+            let mut activity = get_player(self.player);
+            activity.cancel_wager(self.ledger, amount, at, previous_game_nanos);
+            set_player(self.player, activity);
+        }
+        PENDING_EXPOSURE.with_borrow_mut(|p| {
+            if let Some(exposure) = p.get_mut(&self.ledger) {
+                *exposure -= self.exposure;
+                if *exposure == 0 {
+                    p.remove(&self.ledger);
+                }
+            }
+        });
+    }
+}
+
+fn pending_games(player: Principal) -> usize {
+    PENDING_GAMES.with_borrow(|p| p.get(&player).copied().unwrap_or_default())
+}
+
+fn pending_exposure(ledger: Principal) -> u64 {
+    PENDING_EXPOSURE.with_borrow(|p| p.get(&ledger).copied().unwrap_or_default())
+}
+
+/// A transfer of a referral claim in flight, counted until it is dropped.
+struct ClaimAttempt {
+    key: (Principal, Principal),
+}
+
+impl ClaimAttempt {
+    fn new(referrer: Principal, ledger: Principal) -> Self {
+        let key = (referrer, ledger);
+        CLAIM_ATTEMPTS.with_borrow_mut(|a| *a.entry(key).or_default() += 1);
+        ClaimAttempt { key }
+    }
+}
+
+impl Drop for ClaimAttempt {
+    fn drop(&mut self) {
+        CLAIM_ATTEMPTS.with_borrow_mut(|a| {
+            if let Some(attempts) = a.get_mut(&self.key) {
+                *attempts -= 1;
+                if *attempts == 0 {
+                    a.remove(&self.key);
+                }
+            }
+        });
+    }
+}
+
+fn claim_attempts(referrer: Principal, ledger: Principal) -> usize {
+    CLAIM_ATTEMPTS.with_borrow(|a| a.get(&(referrer, ledger)).copied().unwrap_or_default())
+}
+
+/// A tool result with a short text for humans followed by the JSON `payload` for agents. The
+/// payload is also the structured content of the result, which the tool's output schema
+/// describes; the JSON content is kept for clients that do not read structured content.
+fn text_and_json<T: Serialize>(text: String, payload: T) -> Result<CallToolResult, Error> {
+    let payload = serde_json::to_value(payload)
+        .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+    let json =
+        Content::json(&payload).map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+    let mut result = CallToolResult::structured(payload);
+    result.content = vec![Content::text(text), json];
+    Ok(result)
+}
+
+fn get_game_hash(plaintext: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(plaintext);
+    let result = hasher.finalize();
+    hex::encode(result)
+}
+
+/// Verifies a seeded game when `client_seed` is given, where `plaintext` is the server seed,
+/// and a game committed to a pre-drawn result otherwise.
+fn verify(hash: &str, plaintext: &str, client_seed: Option<&str>) -> GameVerification {
+    let record = find_record(hash);
+
+    let parsed = match client_seed {
+        Some(client_seed) => {
+            GameType::parse_server_seed(plaintext).map(|(game_type, random_hex, jackpot_odds)| {
+                (
+                    game_type,
+                    game_type.draw_seeded(random_hex, client_seed),
+                    random_hex,
+                    jackpot_odds,
+                    jackpot_odds
+                        .is_some_and(|odds| game::jackpot_hit(random_hex, client_seed, odds)),
+                )
+            })
+        }
+        None => GameType::parse_plaintext(plaintext).map(|(game_type, result, random_hex)| {
+            (game_type, result.to_string(), random_hex, None, false)
+        }),
+    };
+    let Some((game_type, result, random_hex, jackpot_odds, jackpot_hit)) = parsed else {
+        return GameVerification {
+            verified: false,
+            hash_matches: false,
+            record_matches: false,
+            computed_hash: None,
+            computed_result: None,
+            computed_jackpot_hit: None,
+            record,
+            message: "Invalid plaintext. Expected the server seed `<GameType>|<Random_Hex>` or `<GameType>|<Random_Hex>|<Jackpot_Odds>` with a client seed, or `<GameResult>|<Random_Hex>` or `<GameType>|<GameResult>|<Random_Hex>` without one.".to_string(),
+        };
+    };
+
+    let record_matches = record.as_ref().is_some_and(|record| {
+        record.game_type.unwrap_or_default() == game_type
+            && record.client_seed.as_deref() == client_seed
+            && record.result == result
+            && record.random_hex == random_hex
+            && record.jackpot_odds == jackpot_odds
+            && record.jackpot_won.is_some() == jackpot_hit
+    });
+    let computed_hash = get_game_hash(plaintext);
+    let hash_matches = computed_hash == hash;
+
+    let message = match (hash_matches, &record, record_matches) {
+        (false, _, _) => "The plaintext does not hash to the given game hash.",
+        (true, None, _) => {
+            "The hash matches the plaintext, but no finished game with this hash was found."
+        }
+        (true, Some(_), false) => {
+            "The hash matches the plaintext, but it differs from the recorded game."
+        }
+        (true, Some(_), true) => {
+            "Verified. The plaintext matches both the game hash and the recorded game."
+        }
+    };
+
+    GameVerification {
+        verified: hash_matches && record_matches,
+        hash_matches,
+        record_matches,
+        computed_hash: Some(computed_hash),
+        computed_result: Some(result),
+        computed_jackpot_hit: jackpot_odds.map(|_| jackpot_hit),
+        record,
+        message: message.to_string(),
+    }
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::GameOutcome;
+    use icrc_ledger_types::icrc2::allowance::Allowance;
+    use std::collections::BTreeMap;
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+    use std::task::{Context as TaskContext, Poll, Waker};
+
+    const FEE: u64 = 10_000;
+    const BET: u64 = 100_000_000;
+    const BANKROLL: u64 = 100 * BET;
+    const PLAYER_FUNDS: u64 = 10 * BET;
+    const START_NANOS: u64 = 1_700_000_000_000_000_000;
+    const RANDOM_BYTES: [u8; 32] = [7; 32];
+    const CLIENT_SEED: &str = "seed";
+
+    fn house() -> Principal {
+        Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap()
+    }
+
+    fn player() -> Principal {
+        Principal::anonymous()
+    }
+
+    /// An in-memory ICRC-1/ICRC-2 ledger. Amounts move between owners' default accounts and
+    /// every transfer or deposit costs `FEE`.
+    #[derive(Default)]
+    struct MockLedger {
+        balances: RefCell<BTreeMap<Principal, u64>>,
+        /// Allowances given to the house, by owner.
+        allowances: RefCell<BTreeMap<Principal, u64>>,
+        /// Transfers sent by the house with their block index, for deduplication.
+        transfers: RefCell<Vec<(TransferArg, u64)>>,
+        blocks: Cell<u64>,
+        /// Rejects every `transfer` call as if the ledger were unreachable.
+        unavailable: Cell<bool>,
+        /// Executes the next `transfer` but fails the call, as if its reply were lost.
+        lose_reply: Cell<bool>,
+        /// Transfers created before this time are rejected as too old.
+        window_start: Cell<u64>,
+        /// Rejects the next `transfer_from` call with this error.
+        transfer_from_error: RefCell<Option<TransferFromError>>,
+        /// Suspends every call once, as an inter-canister call does, so tests can interleave
+        /// two calls.
+        suspend: Cell<bool>,
+    }
+
+    impl MockLedger {
+        fn balance(&self, owner: Principal) -> u64 {
+            self.balances
+                .borrow()
+                .get(&owner)
+                .copied()
+                .unwrap_or_default()
+        }
+
+        fn allowance_of(&self, owner: Principal) -> u64 {
+            self.allowances
+                .borrow()
+                .get(&owner)
+                .copied()
+                .unwrap_or_default()
+        }
+
+        async fn call(&self) {
+            if self.suspend.get() {
+                Suspend(false).await;
+            }
+        }
+
+        fn move_funds(&self, from: Principal, to: Principal, amount: u64) -> u64 {
+            let mut balances = self.balances.borrow_mut();
+            *balances.entry(from).or_default() -= amount + FEE;
+            *balances.entry(to).or_default() += amount;
+            self.blocks.set(self.blocks.get() + 1);
+            self.blocks.get()
+        }
+    }
+
+    impl Ledger for MockLedger {
+        fn self_account(&self) -> Account {
+            Account {
+                owner: house(),
+                subaccount: None,
+            }
+        }
+
+        async fn fee(&self, _: Principal) -> CallResult<Nat> {
+            self.call().await;
+            Ok(Nat::from(FEE))
+        }
+
+        async fn decimals(&self, _: Principal) -> CallResult<u8> {
+            Ok(8)
+        }
+
+        async fn symbol(&self, _: Principal) -> CallResult<String> {
+            Ok("ICP".to_string())
+        }
+
+        async fn balance_of(&self, _: Principal, account: Account) -> CallResult<Nat> {
+            self.call().await;
+            Ok(Nat::from(self.balance(account.owner)))
+        }
+
+        async fn allowance(&self, _: Principal, args: AllowanceArgs) -> CallResult<Allowance> {
+            self.call().await;
+            Ok(Allowance {
+                allowance: Nat::from(self.allowance_of(args.account.owner)),
+                expires_at: None,
+            })
+        }
+
+        async fn transfer(
+            &self,
+            _: Principal,
+            args: TransferArg,
+        ) -> CallResult<Result<Nat, TransferError>> {
+            self.call().await;
+            if self.unavailable.get() {
+                return Err((2, "The ledger is unavailable".to_string()));
+            }
+            if args.created_at_time.unwrap_or_default() < self.window_start.get() {
+                return Ok(Err(TransferError::TooOld));
+            }
+            if let Some((_, block)) = self.transfers.borrow().iter().find(|(arg, _)| *arg == args) {
+                return Ok(Err(TransferError::Duplicate {
+                    duplicate_of: Nat::from(*block),
+                }));
+            }
+
+            let amount = u64::try_from(args.amount.0.clone()).unwrap();
+            let balance = self.balance(house());
+            if balance < amount + FEE {
+                return Ok(Err(TransferError::InsufficientFunds {
+                    balance: Nat::from(balance),
+                }));
+            }
+            let block = self.move_funds(house(), args.to.owner, amount);
+            self.transfers.borrow_mut().push((args, block));
+            if self.lose_reply.replace(false) {
+                return Err((2, "The reply was lost".to_string()));
+            }
+            Ok(Ok(Nat::from(block)))
+        }
+
+        async fn transfer_from(
+            &self,
+            _: Principal,
+            args: TransferFromArgs,
+        ) -> CallResult<Result<Nat, TransferFromError>> {
+            self.call().await;
+            if let Some(err) = self.transfer_from_error.take() {
+                return Ok(Err(err));
+            }
+            let owner = args.from.owner;
+            let amount = u64::try_from(args.amount.0).unwrap();
+            let allowance = self.allowance_of(owner);
+            if allowance < amount + FEE {
+                return Ok(Err(TransferFromError::InsufficientAllowance {
+                    allowance: Nat::from(allowance),
+                }));
+            }
+            let balance = self.balance(owner);
+            if balance < amount + FEE {
+                return Ok(Err(TransferFromError::InsufficientFunds {
+                    balance: Nat::from(balance),
+                }));
+            }
+            self.allowances
+                .borrow_mut()
+                .insert(owner, allowance - amount - FEE);
+            Ok(Ok(Nat::from(self.move_funds(owner, args.to.owner, amount))))
+        }
+    }
+
+    /// Pending on the first poll, ready on the next.
+    struct Suspend(bool);
+
+    impl Future for Suspend {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut TaskContext<'_>) -> Poll<()> {
+            if std::mem::replace(&mut self.0, true) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    struct MockClock(Cell<u64>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    struct MockRandomness;
+
+    impl Randomness for MockRandomness {
+        async fn random_bytes(&self) -> Result<Vec<u8>, String> {
+            Ok(RANDOM_BYTES.to_vec())
+        }
+    }
+
+    type TestServer = OddEven<MockLedger, MockClock, MockRandomness>;
+
+    /// Polls a future once and returns its output if it completed.
+    fn poll_once<F: Future>(future: Pin<&mut F>) -> Option<F::Output> {
+        match future.poll(&mut TaskContext::from_waker(Waker::noop())) {
+            Poll::Ready(output) => Some(output),
+            Poll::Pending => None,
+        }
+    }
+
+    /// Runs a future to completion. A suspended mock is ready on the next poll.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        loop {
+            if let Some(output) = poll_once(future.as_mut()) {
+                return output;
+            }
+        }
+    }
+
+    /// A server with a funded bankroll and a player who approved `allowance` to the house.
+    /// The jackpot is disabled so payouts only depend on the guess.
+    fn server(allowance: u64) -> TestServer {
+        set_config(
+            Config::default()
+                .apply(ConfigUpdate {
+                    jackpot_odds: Some(0),
+                    ..Default::default()
+                })
+                .unwrap(),
+        );
+
+        let ledger = MockLedger::default();
+        ledger
+            .balances
+            .borrow_mut()
+            .extend([(house(), BANKROLL), (player(), PLAYER_FUNDS)]);
+        ledger.allowances.borrow_mut().insert(player(), allowance);
+        OddEven {
+            ledger,
+            clock: MockClock(Cell::new(START_NANOS)),
+            randomness: MockRandomness,
+        }
+    }
+
+    fn bet() -> StartRequest {
+        StartRequest {
+            amount: BET,
+            game_type: None,
+            ledger_canister_id: None,
+            referrer: None,
+        }
+    }
+
+    fn start(server: &TestServer) -> Option<GameId> {
+        block_on(server.start(player(), bet())).unwrap();
+        player_games(player()).last().map(|(game_id, _)| *game_id)
+    }
+
+    fn update_config(update: ConfigUpdate) {
+        set_config(get_config().apply(update).unwrap());
+    }
+
+    fn play(server: &TestServer, game_id: GameId, guess: &str) {
+        block_on(server.play(
+            player(),
+            PlayRequest {
+                game_id: Some(game_id),
+                guess: guess.to_string(),
+                client_seed: Some(CLIENT_SEED.to_string()),
+            },
+        ))
+        .unwrap();
+    }
+
+    fn winning_guess() -> String {
+        GameType::OddEven.draw_seeded(&encode(RANDOM_BYTES), CLIENT_SEED)
+    }
+
+    fn losing_guess() -> &'static str {
+        if winning_guess() == "Odd" {
+            "Even"
+        } else {
+            "Odd"
+        }
+    }
+
+    fn last_record() -> GameRecord {
+        player_history(player(), 0, 1).pop().unwrap()
+    }
+
+    /// Checks that `result` carries a structured value with the fields of the output schema of
+    /// `T`, also given as the JSON content, and returns it.
+    fn structured_result<T: JsonSchema>(result: &CallToolResult) -> Value {
+        let value = result.structured_content.clone().unwrap();
+        let schema = schema_for_type::<T>();
+        let mut properties: Vec<&String> =
+            schema["properties"].as_object().unwrap().keys().collect();
+        let mut fields: Vec<&String> = value.as_object().unwrap().keys().collect();
+        properties.sort();
+        fields.sort();
+        assert_eq!(fields, properties);
+        let json = result.content[1].as_text().unwrap();
+        assert_eq!(serde_json::from_str::<Value>(json).unwrap(), value);
+        value
+    }
+
+    #[test]
+    fn start_and_play_results_match_their_output_schema() {
+        let server = server(BET + FEE);
+        let result = block_on(server.start(player(), bet())).unwrap();
+        let started = structured_result::<StartResult>(&result);
+        assert_eq!(started["status"], "Started");
+
+        let result = block_on(server.play(
+            player(),
+            PlayRequest {
+                game_id: started["game_id"].as_u64(),
+                guess: winning_guess(),
+                client_seed: Some(CLIENT_SEED.to_string()),
+            },
+        ))
+        .unwrap();
+        let played = structured_result::<PlayResult>(&result);
+        assert_eq!(played["status"], "Paid");
+        assert_eq!(played["payout"], 2 * BET);
+
+        let result = block_on(server.start(player(), StartRequest { amount: 1, ..bet() })).unwrap();
+        let rejected = structured_result::<StartResult>(&result);
+        assert_eq!(rejected["status"], "Rejected");
+    }
+
+    #[test]
+    fn self_exclusion_follows_the_clock() {
+        let server = server(BET + FEE);
+        server.self_exclude(player(), 2);
+
+        server.clock.0.set(START_NANOS + 2 * DAY_NANOS - 1);
+        assert_eq!(start(&server), None);
+
+        server.clock.0.set(START_NANOS + 2 * DAY_NANOS);
+        assert!(start(&server).is_some());
+    }
+
+    #[test]
+    fn rejects_tokens_with_too_many_decimals() {
+        let config = |token_decimals| Config {
+            token_decimals,
+            ..Config::default()
+        };
+        assert!(config(38).apply(ConfigUpdate::default()).is_ok());
+        assert!(config(39).apply(ConfigUpdate::default()).is_err());
+        assert!(TokenConfig {
+            decimals: 255,
+            ..config(8).default_token()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn win_pays_twice_the_bet() {
+        let server = server(BET + FEE);
+        let game_id = start(&server).unwrap();
+        assert_eq!(server.ledger.balance(house()), BANKROLL + BET);
+
+        play(&server, game_id, &winning_guess());
+
+        assert!(player_games(player()).is_empty());
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS - FEE + BET);
+        assert_eq!(server.ledger.balance(house()), BANKROLL - BET - FEE);
+        let record = last_record();
+        assert!(matches!(record.outcome, GameOutcome::Win));
+        assert_eq!(record.payout, 2 * BET);
+        assert_eq!(record.block_index, Some(server.ledger.blocks.get()));
+        let totals = get_totals(get_config().ledger_canister_id);
+        assert_eq!(totals.total_wagered, BET);
+        assert_eq!(totals.total_paid_out, 2 * BET);
+    }
+
+    #[test]
+    fn loss_keeps_the_bet() {
+        let server = server(BET + FEE);
+        let game_id = start(&server).unwrap();
+
+        play(&server, game_id, losing_guess());
+
+        assert!(player_games(player()).is_empty());
+        assert!(server.ledger.transfers.borrow().is_empty());
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS - BET - FEE);
+        assert_eq!(server.ledger.balance(house()), BANKROLL + BET);
+        let record = last_record();
+        assert!(matches!(record.outcome, GameOutcome::Loss));
+        assert_eq!(record.payout, 0);
+    }
+
+    #[test]
+    fn expired_game_is_refunded_by_the_sweep() {
+        let server = server(BET + FEE);
+        let game_id = start(&server).unwrap();
+        server
+            .clock
+            .0
+            .set(START_NANOS + get_config().expiry_nanos + 1);
+
+        play(&server, game_id, &winning_guess());
+        assert!(get(game_id).unwrap().state() == GameState::Started);
+
+        let report = block_on(server.sweep_expired_games()).unwrap();
+        assert_eq!(report.refunded, 1);
+        assert_eq!(report.failed, 0);
+        assert!(player_games(player()).is_empty());
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS - FEE);
+        let record = last_record();
+        assert!(matches!(record.outcome, GameOutcome::Refunded));
+        assert_eq!(record.payout, BET);
+    }
+
+    #[test]
+    fn insufficient_allowance_starts_no_game() {
+        // The allowance covers the bet but not the ledger fee.
+        let server = server(BET);
+
+        assert_eq!(start(&server), None);
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS);
+        assert_eq!(server.ledger.balance(house()), BANKROLL);
+        assert_eq!(server.ledger.blocks.get(), 0);
+        assert_eq!(get_totals(get_config().ledger_canister_id).total_wagered, 0);
+    }
+
+    #[test]
+    fn failed_payout_stays_pending_until_retried() {
+        let server = server(BET + FEE);
+        let game_id = start(&server).unwrap();
+        server.ledger.unavailable.set(true);
+
+        play(&server, game_id, &winning_guess());
+
+        assert!(matches!(
+            get(game_id).unwrap().state(),
+            GameState::PayoutPending { amount, refund: false, .. } if amount == 2 * BET
+        ));
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS - BET - FEE);
+
+        server.ledger.unavailable.set(false);
+        block_on(server.retry_payout(player())).unwrap();
+
+        assert!(player_games(player()).is_empty());
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS - FEE + BET);
+        let record = last_record();
+        assert!(matches!(record.outcome, GameOutcome::Win));
+        assert_eq!(record.payout, 2 * BET);
+
+        // Nothing is left to pay, so a second retry sends nothing.
+        block_on(server.retry_payout(player())).unwrap();
+        assert_eq!(server.ledger.transfers.borrow().len(), 1);
+    }
+
+    #[test]
+    fn referrer_earns_a_share_of_the_house_edge() {
+        let server = server(BET + FEE);
+        let referrer = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        block_on(server.start(
+            player(),
+            StartRequest {
+                amount: BET,
+                game_type: Some(GameType::Dice),
+                ledger_canister_id: None,
+                referrer: Some(referrer.to_text()),
+            },
+        ))
+        .unwrap();
+        assert_eq!(get_referrer(player()), Some(referrer));
+
+        let (game_id, _) = player_games(player()).pop().unwrap();
+        let roll = GameType::Dice.draw_seeded(&encode(RANDOM_BYTES), CLIENT_SEED);
+        play(&server, game_id, if roll == "1" { "2" } else { "1" });
+
+        // 20% of the 5% house edge of the bet.
+        let ledger = get_config().ledger_canister_id;
+        let reward = BET / 100;
+        assert_eq!(referral_rewards_owed(ledger), reward);
+
+        block_on(server.claim_referral_rewards(referrer)).unwrap();
+        assert_eq!(server.ledger.balance(referrer), reward - FEE);
+        assert_eq!(referral_rewards_owed(ledger), 0);
+        assert_eq!(referral_rewards(referrer)[0].1.total_claimed, reward);
+    }
+
+    #[test]
+    fn only_games_with_a_house_edge_feed_the_jackpot() {
+        let server = server(u64::MAX);
+        // Neither game hits at these odds.
+        update_config(ConfigUpdate {
+            cooldown_secs: Some(0),
+            jackpot_odds: Some(1_000),
+            ..Default::default()
+        });
+        let ledger = get_config().ledger_canister_id;
+
+        let game_id = start(&server).unwrap();
+        play(&server, game_id, losing_guess());
+        assert_eq!(get_jackpot(ledger).pool, 0);
+
+        block_on(server.start(
+            player(),
+            StartRequest {
+                game_type: Some(GameType::Dice),
+                ..bet()
+            },
+        ))
+        .unwrap();
+        let (game_id, _) = player_games(player()).pop().unwrap();
+        let roll = GameType::Dice.draw_seeded(&encode(RANDOM_BYTES), CLIENT_SEED);
+        play(&server, game_id, if roll == "1" { "2" } else { "1" });
+        assert_eq!(get_jackpot(ledger).pool, BET / 100);
+    }
+
+    #[test]
+    fn referral_claim_with_a_lost_reply_is_not_paid_twice() {
+        let server = server(BET + FEE);
+        let referrer = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let ledger = get_config().ledger_canister_id;
+        add_referral_reward(referrer, ledger, BET);
+
+        server.ledger.lose_reply.set(true);
+        block_on(server.claim_referral_rewards(referrer)).unwrap();
+        assert_eq!(server.ledger.balance(referrer), BET - FEE);
+        // Still owed until the ledger confirms the transfer.
+        assert_eq!(referral_rewards_owed(ledger), BET);
+
+        // The retry resends the same transfer, which the ledger reports as a duplicate.
+        add_referral_reward(referrer, ledger, BET);
+        block_on(server.claim_referral_rewards(referrer)).unwrap();
+        assert_eq!(server.ledger.balance(referrer), BET - FEE);
+        assert_eq!(server.ledger.transfers.borrow().len(), 1);
+        assert_eq!(referral_rewards_owed(ledger), BET);
+
+        // Rewards earned meanwhile go out in a claim of their own.
+        block_on(server.claim_referral_rewards(referrer)).unwrap();
+        assert_eq!(server.ledger.balance(referrer), 2 * (BET - FEE));
+        assert_eq!(referral_rewards_owed(ledger), 0);
+        assert_eq!(referral_rewards(referrer)[0].1.total_claimed, 2 * BET);
+    }
+
+    #[test]
+    fn concurrent_referral_claims_past_the_transaction_window_pay_once() {
+        let server = server(BET + FEE);
+        let referrer = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let ledger = get_config().ledger_canister_id;
+        add_referral_reward(referrer, ledger, BET);
+        server.ledger.unavailable.set(true);
+        block_on(server.claim_referral_rewards(referrer)).unwrap();
+
+        let now = START_NANOS + 2 * DAY_NANOS;
+        server.clock.0.set(now);
+        server.ledger.window_start.set(now - DAY_NANOS);
+        server.ledger.unavailable.set(false);
+        server.ledger.suspend.set(true);
+
+        // Both attempts send the stale claim and get `TooOld`. The first one refreshes its
+        // time; the second one, resumed later, reuses it instead of minting its own.
+        let mut first = pin!(server.pay_referral_rewards(referrer, ledger));
+        let mut second = pin!(server.pay_referral_rewards(referrer, ledger));
+        for _ in 0..3 {
+            assert!(poll_once(first.as_mut()).is_none());
+            server.clock.0.set(server.clock.0.get() + 1);
+            assert!(poll_once(second.as_mut()).is_none());
+        }
+
+        block_on(first).unwrap();
+        block_on(second).unwrap();
+        assert_eq!(server.ledger.transfers.borrow().len(), 1);
+        assert_eq!(server.ledger.balance(referrer), BET - FEE);
+        assert_eq!(referral_rewards_owed(ledger), 0);
+    }
+
+    #[test]
+    fn rejected_referral_claim_gives_the_rewards_back() {
+        let server = server(BET + FEE);
+        let referrer = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+        let ledger = get_config().ledger_canister_id;
+        add_referral_reward(referrer, ledger, BET);
+        server.ledger.balances.borrow_mut().insert(house(), 0);
+
+        block_on(server.claim_referral_rewards(referrer)).unwrap();
+        let rewards = referral_rewards(referrer).pop().unwrap().1;
+        assert_eq!(rewards.claimable, BET);
+        assert_eq!(rewards.total_claimed, 0);
+        assert!(rewards.pending_claim.is_none());
+    }
+
+    #[test]
+    fn concurrent_starts_can_not_overdraw_the_bankroll() {
+        // The bankroll covers the exposure of one odd-even bet, its payout minus the bet, and
+        // the payout fee.
+        let server = server(2 * (BET + FEE));
+        server
+            .ledger
+            .balances
+            .borrow_mut()
+            .insert(house(), BET + FEE);
+        server.ledger.suspend.set(true);
+        update_config(ConfigUpdate {
+            cooldown_secs: Some(0),
+            ..Default::default()
+        });
+        let ledger = get_config().ledger_canister_id;
+
+        // The first call passes the bankroll check and waits for the ledger.
+        let mut first = pin!(server.start(player(), bet()));
+        while pending_exposure(ledger) == 0 {
+            assert!(poll_once(first.as_mut()).is_none());
+        }
+
+        block_on(server.start(player(), bet())).unwrap();
+        assert!(player_games(player()).is_empty());
+
+        block_on(first).unwrap();
+        assert_eq!(player_games(player()).len(), 1);
+        assert_eq!(pending_exposure(ledger), 0);
+    }
+
+    #[test]
+    fn payout_past_the_transaction_window_is_sent_with_a_fresh_time() {
+        let server = server(BET + FEE);
+        let game_id = start(&server).unwrap();
+        server.ledger.unavailable.set(true);
+        play(&server, game_id, &winning_guess());
+
+        let now = START_NANOS + 2 * DAY_NANOS;
+        server.clock.0.set(now);
+        server.ledger.window_start.set(now - DAY_NANOS);
+        server.ledger.unavailable.set(false);
+        block_on(server.retry_payout(player())).unwrap();
+
+        assert!(player_games(player()).is_empty());
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS - FEE + BET);
+        assert_eq!(
+            server.ledger.transfers.borrow()[0].0.created_at_time,
+            Some(now)
+        );
+        assert!(matches!(last_record().outcome, GameOutcome::Win));
+    }
+
+    #[test]
+    fn concurrent_payouts_past_the_transaction_window_pay_once() {
+        let server = server(BET + FEE);
+        let game_id = start(&server).unwrap();
+        server.ledger.unavailable.set(true);
+        play(&server, game_id, &winning_guess());
+
+        let now = START_NANOS + 2 * DAY_NANOS;
+        server.clock.0.set(now);
+        server.ledger.window_start.set(now - DAY_NANOS);
+        server.ledger.unavailable.set(false);
+        server.ledger.suspend.set(true);
+
+        // Both attempts send the stale time and get `TooOld`. The first one refreshes the time;
+        // the second one, resumed later, reuses it instead of minting its own.
+        let info = get(game_id).unwrap();
+        let mut first = pin!(server.settle_payout(game_id, info.clone()));
+        let mut second = pin!(server.settle_payout(game_id, info));
+        assert!(poll_once(first.as_mut()).is_none());
+        assert!(poll_once(second.as_mut()).is_none());
+        assert!(poll_once(first.as_mut()).is_none());
+        server.clock.0.set(now + 1);
+        assert!(poll_once(second.as_mut()).is_none());
+
+        block_on(first).unwrap();
+        block_on(second).unwrap();
+        assert_eq!(server.ledger.transfers.borrow().len(), 1);
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS - FEE + BET);
+        assert!(player_games(player()).is_empty());
+    }
+
+    #[test]
+    fn concurrent_starts_respect_the_cooldown_and_wager_caps() {
+        let server = server(3 * (BET + FEE));
+        server.ledger.suspend.set(true);
+
+        // The second call starts during the cooldown of the first game.
+        let mut first = pin!(server.start(player(), bet()));
+        assert!(poll_once(first.as_mut()).is_none());
+        block_on(server.start(player(), bet())).unwrap();
+        assert!(player_games(player()).is_empty());
+        block_on(first).unwrap();
+        assert_eq!(player_games(player()).len(), 1);
+
+        // Without a cooldown, the daily cap still counts the bet in flight.
+        update_config(ConfigUpdate {
+            cooldown_secs: Some(0),
+            daily_wager_limit: Some(2 * BET),
+            ..Default::default()
+        });
+        let mut first = pin!(server.start(player(), bet()));
+        assert!(poll_once(first.as_mut()).is_none());
+        block_on(server.start(player(), bet())).unwrap();
+        assert_eq!(player_games(player()).len(), 1);
+        block_on(first).unwrap();
+        assert_eq!(player_games(player()).len(), 2);
+        assert_eq!(
+            get_player(player()).wagered(get_config().ledger_canister_id, START_NANOS),
+            (2 * BET, 2 * BET)
+        );
+    }
+
+    #[test]
+    fn wager_caps_apply_to_every_token() {
+        let server = server(u64::MAX);
+        update_config(ConfigUpdate {
+            cooldown_secs: Some(0),
+            daily_wager_limit: Some(BET),
+            ..Default::default()
+        });
+        let other = Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap();
+        set_token(
+            other,
+            TokenConfig {
+                symbol: "ckBTC".to_string(),
+                decimals: 8,
+                min_amount: BET / 10,
+                max_amount: BET,
+                daily_wager_limit: BET,
+                weekly_wager_limit: 0,
+            },
+        );
+        let other_bet = || StartRequest {
+            ledger_canister_id: Some(other.to_text()),
+            ..bet()
+        };
+
+        assert!(start(&server).is_some());
+        block_on(server.start(player(), bet())).unwrap();
+        assert_eq!(player_games(player()).len(), 1);
+
+        // The other token has a cap of its own, which the player reaches too.
+        block_on(server.start(player(), other_bet())).unwrap();
+        assert_eq!(player_games(player()).len(), 2);
+        block_on(server.start(player(), other_bet())).unwrap();
+        assert_eq!(player_games(player()).len(), 2);
+        assert_eq!(get_player(player()).wagered(other, START_NANOS), (BET, BET));
+    }
+
+    #[test]
+    fn rejected_deposit_gives_the_wager_back() {
+        let server = server(BET);
+        update_config(ConfigUpdate {
+            daily_wager_limit: Some(BET),
+            ..Default::default()
+        });
+
+        assert_eq!(start(&server), None);
+        let activity = get_player(player());
+        assert_eq!(
+            activity.wagered(get_config().ledger_canister_id, START_NANOS),
+            (0, 0)
+        );
+        assert_eq!(activity.last_game_nanos, 0);
+
+        // Neither the cooldown nor the cap holds back the next attempt.
+        server
+            .ledger
+            .allowances
+            .borrow_mut()
+            .insert(player(), BET + FEE);
+        assert!(start(&server).is_some());
+    }
+
+    #[test]
+    fn rejected_deposit_starts_no_game() {
+        let server = server(BET + FEE);
+        server
+            .ledger
+            .transfer_from_error
+            .replace(Some(TransferFromError::TemporarilyUnavailable));
+
+        // A rejection for the player, not a tool error.
+        assert!(block_on(server.start(player(), bet())).is_ok());
+        assert!(player_games(player()).is_empty());
+        assert_eq!(server.ledger.balance(player()), PLAYER_FUNDS);
+        assert_eq!(get_player(player()).last_game_nanos, 0);
+    }
+
+    #[test]
+    fn closing_games_refunds_bets_and_sends_pending_payouts() {
+        let server = server(2 * (BET + FEE));
+        update_config(ConfigUpdate {
+            cooldown_secs: Some(0),
+            ..Default::default()
+        });
+        let won = start(&server).unwrap();
+        server.ledger.unavailable.set(true);
+        play(&server, won, &winning_guess());
+        server.ledger.unavailable.set(false);
+        let open = start(&server).unwrap();
+
+        let lines = block_on(server.close_player_games(player()));
+
+        assert_eq!(lines.len(), 2);
+        assert!(
+            lines[0].contains(&format!("payout of {}", 2 * BET)),
+            "{}",
+            lines[0]
+        );
+        assert!(
+            lines[1].contains(&format!("refund of {BET}")),
+            "{}",
+            lines[1]
+        );
+        assert!(player_games(player()).is_empty());
+        assert_eq!(
+            server.ledger.balance(player()),
+            PLAYER_FUNDS - 2 * FEE + BET
+        );
+        let records = player_history(player(), 0, 2);
+        assert!(matches!(records[0].outcome, GameOutcome::Refunded));
+        assert_eq!(records[0].game_id, Some(open));
+        assert!(matches!(records[1].outcome, GameOutcome::Win));
+    }
+
+    #[test]
+    fn concurrent_starts_respect_the_open_games_limit() {
+        let server = server(u64::MAX);
+        server
+            .ledger
+            .balances
+            .borrow_mut()
+            .insert(player(), 2 * PLAYER_FUNDS);
+        update_config(ConfigUpdate {
+            cooldown_secs: Some(0),
+            ..Default::default()
+        });
+        for _ in 1..MAX_OPEN_GAMES_PER_PLAYER {
+            start(&server).unwrap();
+        }
+        server.ledger.suspend.set(true);
+
+        // The last slot goes to the first call, which waits for the ledger.
+        let mut first = pin!(server.start(player(), bet()));
+        assert!(poll_once(first.as_mut()).is_none());
+        block_on(server.start(player(), bet())).unwrap();
+        assert_eq!(player_games(player()).len(), MAX_OPEN_GAMES_PER_PLAYER - 1);
+
+        block_on(first).unwrap();
+        assert_eq!(player_games(player()).len(), MAX_OPEN_GAMES_PER_PLAYER);
+        assert_eq!(pending_games(player()), 0);
+    }
+}
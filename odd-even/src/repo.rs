@@ -1,20 +1,115 @@
 use candid::{CandidType, Decode, Encode, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{storable::Bound, DefaultMemoryImpl, StableBTreeMap, Storable};
-use serde::Deserialize;
+use ic_stable_structures::{
+    storable::Bound, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::config::{Config, TokenConfig, DAY_NANOS};
+use crate::game::{apply_bps, GameType};
+
+/// Lifecycle of a game. Every transition is persisted before the next ledger call, so a
+/// game interrupted between two calls resumes from its last recorded state.
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq)]
+pub enum GameState {
+    /// The bet is deposited and the game waits for a guess.
+    Started,
+    /// The guess is recorded and the result revealed. Nothing is owed to the player.
+    Revealed,
+    /// A payout (a win or a refund) is owed. `created_at_time` is reused on every attempt
+    /// so the ledger deduplicates retries, until it falls out of the ledger's transaction
+    /// window.
+    PayoutPending {
+        amount: u64,
+        created_at_time: u64,
+        refund: bool,
+    },
+    Paid {
+        amount: u64,
+        block_index: u64,
+    },
+    Refunded {
+        amount: u64,
+        block_index: u64,
+    },
+}
+
+pub type GameId = u64;
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct GameInfo {
+    // Set for every game keyed by id; entries of the legacy principal-keyed map get it when
+    // they are migrated.
+    pub player: Option<Principal>,
     pub amount: u64,
     pub timestamp_nanos: u64,
-    pub result: String,
+    // Drawn at `start` for games committed before client seeds existed; otherwise drawn
+    // from the client seed when the game is played.
+    pub result: Option<String>,
     pub random_hex: String,
     pub hash: String,
+    // Games stored before the state machine existed have neither field and are `Started`.
+    pub state: Option<GameState>,
+    pub guess: Option<String>,
+    // Games stored before game types existed are odd-even.
+    pub game_type: Option<GameType>,
+    pub client_seed: Option<String>,
+    // Odds committed to by the hash of a game eligible for the jackpot.
+    pub jackpot_odds: Option<u64>,
+    // Jackpot paid with the game payout when the jackpot draw hits.
+    pub jackpot_won: Option<u64>,
+    // Games started before multi-token betting are on the default ledger of the config, which
+    // can not change while they are in progress.
+    pub ledger_canister_id: Option<Principal>,
+}
+
+/// Version of the `GameInfo` layout written by this release.
+const GAME_INFO_VERSION: u8 = 2;
+
+/// Stored form of `GameInfo`. A layout change adds a variant, and `from_bytes` migrates the
+/// older variants into the current layout.
+#[derive(CandidType, Deserialize)]
+enum VersionedGameInfo {
+    V2(GameInfo),
 }
 
 impl Storable for GameInfo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&VersionedGameInfo::V2(self.clone())).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        match Decode!(bytes.as_ref(), VersionedGameInfo) {
+            Ok(VersionedGameInfo::V2(info)) => info,
+            // Entries written before the envelope are bare v1 records, possibly with some of
+            // the optional fields added since. Candid decodes both into the current layout.
+            Err(_) => {
+                let mut info = Decode!(bytes.as_ref(), Self).unwrap();
+                info.state.get_or_insert(GameState::Started);
+                info.game_type.get_or_insert(GameType::OddEven);
+                info
+            }
+        }
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// In-progress game of the releases that allowed one game per player, with the layout and
+/// bound they stored it under in memory id 0. Only read to move it to the id-keyed map.
+#[derive(CandidType, Deserialize, Clone)]
+struct LegacyGameInfo {
+    amount: u64,
+    timestamp_nanos: u64,
+    result: String,
+    random_hex: String,
+    hash: String,
+}
+
+impl Storable for LegacyGameInfo {
     fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
@@ -29,26 +124,1528 @@ impl Storable for GameInfo {
     };
 }
 
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub enum GameOutcome {
+    Win,
+    Loss,
+    Refunded,
+    Forfeited,
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct GameRecord {
+    pub player: Principal,
+    pub game_id: Option<GameId>,
+    pub game_type: Option<GameType>,
+    pub amount: u64,
+    pub guess: Option<String>,
+    pub client_seed: Option<String>,
+    pub result: String,
+    pub random_hex: String,
+    pub hash: String,
+    pub outcome: GameOutcome,
+    pub payout: u64,
+    pub block_index: Option<u64>,
+    pub started_at_nanos: u64,
+    pub finished_at_nanos: u64,
+    pub jackpot_odds: Option<u64>,
+    /// Part of the payout won from the jackpot.
+    pub jackpot_won: Option<u64>,
+    /// Empty for games finished before multi-token betting, which used the default ledger.
+    pub ledger_canister_id: Option<Principal>,
+}
+
+impl GameInfo {
+    pub fn state(&self) -> GameState {
+        self.state.clone().unwrap_or(GameState::Started)
+    }
+
+    pub fn player(&self) -> Principal {
+        self.player.expect("game stored without a player")
+    }
+
+    pub fn game_type(&self) -> GameType {
+        self.game_type.unwrap_or_default()
+    }
+
+    /// The ledger the bet was deposited on and the payout is sent from.
+    pub fn ledger(&self) -> Principal {
+        self.ledger_canister_id
+            .unwrap_or_else(|| get_config().ledger_canister_id)
+    }
+
+    /// Amount the house still owes, or may owe, on this game.
+    pub fn liability(&self) -> u64 {
+        match self.state() {
+            GameState::Started => apply_bps(self.amount, self.game_type().game().max_payout_bps()),
+            GameState::PayoutPending { amount, .. } => amount,
+            GameState::Revealed | GameState::Paid { .. } | GameState::Refunded { .. } => 0,
+        }
+    }
+
+    /// Builds the history record of this game. The outcome follows from the state: a game
+    /// that is neither revealed nor paid out is forfeited, and a losing guess paid only the
+    /// jackpot is still a loss.
+    pub fn into_record(self, game_id: GameId, finished_at_nanos: u64) -> GameRecord {
+        let ledger = self.ledger();
+        let guess_lost = match (&self.guess, &self.result) {
+            (Some(guess), Some(result)) => self.game_type().game().payout_bps(guess, result) == 0,
+            _ => false,
+        };
+        let (outcome, payout, block_index) = match self.state() {
+            GameState::Revealed => (GameOutcome::Loss, 0, None),
+            GameState::Paid {
+                amount,
+                block_index,
+            } if guess_lost => (GameOutcome::Loss, amount, Some(block_index)),
+            GameState::Paid {
+                amount,
+                block_index,
+            } => (GameOutcome::Win, amount, Some(block_index)),
+            GameState::Refunded {
+                amount,
+                block_index,
+            } => (GameOutcome::Refunded, amount, Some(block_index)),
+            GameState::Started | GameState::PayoutPending { .. } => {
+                (GameOutcome::Forfeited, 0, None)
+            }
+        };
+
+        GameRecord {
+            player: self.player(),
+            game_id: Some(game_id),
+            game_type: Some(self.game_type()),
+            amount: self.amount,
+            guess: self.guess,
+            client_seed: self.client_seed,
+            result: self.result.unwrap_or_default(),
+            random_hex: self.random_hex,
+            hash: self.hash,
+            outcome,
+            payout,
+            block_index,
+            started_at_nanos: self.timestamp_nanos,
+            finished_at_nanos,
+            jackpot_odds: self.jackpot_odds,
+            jackpot_won: self.jackpot_won,
+            ledger_canister_id: Some(ledger),
+        }
+    }
+}
+
+impl Storable for GameRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// What the house owes on one ledger, kept up to date as games and referral rewards change so
+/// the bankroll checks do not scan every open game.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct OpenTotals {
+    /// Number of in-progress games.
+    pub games: u64,
+    /// Sum of `GameInfo::liability` of the in-progress games.
+    pub exposure: u64,
+    /// Sum of `ReferralRewards::owed` of the referrers.
+    pub referral_rewards: u64,
+}
+
+impl Storable for OpenTotals {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Progress of a statistics rebuild, see `start_stats_rebuild`.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct StatsRebuild {
+    pub running: bool,
+    /// Last history key counted. The records up to it are in the statistics.
+    pub done: Option<(Principal, u64)>,
+}
+
+impl Storable for StatsRebuild {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct HouseTotals {
+    pub total_wagered: u64,
+    pub total_paid_out: u64,
+}
+
+impl Storable for HouseTotals {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct JackpotWin {
+    pub player: Principal,
+    pub game_id: GameId,
+    pub amount: u64,
+    pub timestamp_nanos: u64,
+}
+
+/// The progressive jackpot. `pool` is owed to the next winner and is not part of the bankroll.
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct Jackpot {
+    pub pool: u64,
+    pub total_contributed: u64,
+    pub total_paid_out: u64,
+    pub wins: u64,
+    pub last_win: Option<JackpotWin>,
+}
+
+impl Storable for TokenConfig {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Jackpot {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Referral rewards of a referrer in one token. `claimable` and the pending claim are owed to
+/// the referrer and are not part of the bankroll.
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct ReferralRewards {
+    pub claimable: u64,
+    pub total_earned: u64,
+    pub total_claimed: u64,
+    /// Number of claims opened so far, which numbers the next one.
+    pub claims: u64,
+    pub pending_claim: Option<ReferralClaim>,
+}
+
+impl ReferralRewards {
+    /// Rewards owed to the referrer, including those of a claim not settled yet.
+    pub fn owed(&self) -> u64 {
+        self.claimable
+            .saturating_add(self.pending_claim.as_ref().map_or(0, |claim| claim.amount))
+    }
+}
+
+/// Rewards taken out of `claimable` to be sent, kept until the ledger settles the transfer.
+/// Every attempt sends `amount - fee` with the claim id as memo and the same `created_at_time`,
+/// so the ledger deduplicates a retry of a transfer that went through.
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct ReferralClaim {
+    pub id: u64,
+    pub amount: u64,
+    pub fee: u64,
+    pub created_at_time: u64,
+}
+
+impl Storable for ReferralRewards {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Amounts a player wagered in one token, per UTC day and per 7-day week since the Unix epoch.
+/// A window that has passed counts as empty.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct Wagers {
+    pub day: u64,
+    pub wagered_day: u64,
+    pub week: u64,
+    pub wagered_week: u64,
+}
+
+impl Wagers {
+    /// Returns the amounts wagered in the current day and week.
+    pub fn wagered(&self, now: u64) -> (u64, u64) {
+        let day = now / DAY_NANOS;
+        (
+            if self.day == day { self.wagered_day } else { 0 },
+            if self.week == day / 7 {
+                self.wagered_week
+            } else {
+                0
+            },
+        )
+    }
+}
+
+/// Wagers by ledger, last game and self-exclusion of a player.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct PlayerActivity {
+    pub wagers: BTreeMap<Principal, Wagers>,
+    pub last_game_nanos: u64,
+    pub excluded_until_nanos: u64,
+}
+
+impl PlayerActivity {
+    /// Returns the amounts wagered on `ledger` in the current day and week.
+    pub fn wagered(&self, ledger: Principal, now: u64) -> (u64, u64) {
+        self.wagers
+            .get(&ledger)
+            .map(|wagers| wagers.wagered(now))
+            .unwrap_or_default()
+    }
+
+    pub fn record_wager(&mut self, ledger: Principal, amount: u64, now: u64) {
+        let (wagered_day, wagered_week) = self.wagered(ledger, now);
+        let day = now / DAY_NANOS;
+        self.wagers.insert(
+            ledger,
+            Wagers {
+                day,
+                wagered_day: wagered_day.saturating_add(amount),
+                week: day / 7,
+                wagered_week: wagered_week.saturating_add(amount),
+            },
+        );
+        self.last_game_nanos = now;
+    }
+
+    /// Takes back a wager on `ledger` recorded at `at` for a game that did not start. The last
+    /// game goes back to `previous_game_nanos` unless another one was recorded since.
+    pub fn cancel_wager(
+        &mut self,
+        ledger: Principal,
+        amount: u64,
+        at: u64,
+        previous_game_nanos: u64,
+    ) {
+        let day = at / DAY_NANOS;
+        if let Some(wagers) = self.wagers.get_mut(&ledger) {
+            if wagers.day == day {
+                wagers.wagered_day = wagers.wagered_day.saturating_sub(amount);
+            }
+            if wagers.week == day / 7 {
+                wagers.wagered_week = wagers.wagered_week.saturating_sub(amount);
+            }
+        }
+        if self.last_game_nanos == at {
+            self.last_game_nanos = previous_game_nanos;
+        }
+    }
+}
+
+impl Storable for PlayerActivity {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A controller action, kept in the audit log.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub timestamp_nanos: u64,
+    pub caller: Principal,
+    pub action: String,
+    pub player: Option<Principal>,
+    pub details: String,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Finished games of a player.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct PlayerStats {
+    pub games: u64,
+    pub wins: u64,
+    pub losses: u64,
+    pub wagered: u64,
+    pub paid_out: u64,
+}
+
+impl PlayerStats {
+    pub fn net_profit(&self) -> i64 {
+        (self.paid_out as i128 - self.wagered as i128).clamp(i64::MIN as i128, i64::MAX as i128)
+            as i64
+    }
+
+    /// Share of played games that were won, refunds and forfeits aside.
+    pub fn win_rate(&self) -> f64 {
+        if self.wins + self.losses == 0 {
+            0.0
+        } else {
+            self.wins as f64 / (self.wins + self.losses) as f64
+        }
+    }
+
+    /// Leaderboard key; ascending keys list the highest net profit first.
+    fn rank_key(&self) -> u64 {
+        // Flipping the sign bit maps i64 onto u64 in order.
+        u64::MAX - ((self.net_profit() as u64) ^ (1 << 63))
+    }
+}
+
+impl Storable for PlayerStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone)]
+pub struct OutcomeCount {
+    pub game_type: GameType,
+    pub result: String,
+    pub count: u64,
+}
+
+/// Aggregates over every finished game, updated as games are archived.
+#[derive(CandidType, Deserialize, Clone, Default)]
+pub struct GameStats {
+    pub games: u64,
+    pub wins: u64,
+    pub losses: u64,
+    pub refunds: u64,
+    pub forfeits: u64,
+    pub volume: u64,
+    pub paid_out: u64,
+    /// How often each result was drawn in played games.
+    pub outcomes: Vec<OutcomeCount>,
+}
+
+impl GameStats {
+    fn add(&mut self, record: &GameRecord) {
+        self.games += 1;
+        self.volume = self.volume.saturating_add(record.amount);
+        self.paid_out = self.paid_out.saturating_add(record.payout);
+        match record.outcome {
+            GameOutcome::Win => self.wins += 1,
+            GameOutcome::Loss => self.losses += 1,
+            GameOutcome::Refunded => self.refunds += 1,
+            GameOutcome::Forfeited => self.forfeits += 1,
+        }
+
+        if !matches!(record.outcome, GameOutcome::Win | GameOutcome::Loss) {
+            return;
+        }
+
+        let game_type = record.game_type.unwrap_or_default();
+        match self
+            .outcomes
+            .iter_mut()
+            .find(|o| o.game_type == game_type && o.result == record.result)
+        {
+            Some(outcome) => outcome.count += 1,
+            None => self.outcomes.push(OutcomeCount {
+                game_type,
+                result: record.result.clone(),
+                count: 1,
+            }),
+        }
+    }
+}
+
+impl Storable for GameStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Leaderboard key: (ledger, (PlayerStats::rank_key, player)).
+type RankKey = (Principal, (u64, Principal));
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
 
-    static MAP: RefCell<StableBTreeMap<Principal, GameInfo, Memory>> = RefCell::new(
+    // In-progress games of releases that allowed one game per player. Emptied on upgrade by
+    // `migrate_legacy_games`.
+    static LEGACY_MAP: RefCell<StableBTreeMap<Principal, LegacyGameInfo, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
         )
     );
+
+    static MAP: RefCell<StableBTreeMap<GameId, GameInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+
+    // Open games of each player, keyed by (player, game id).
+    static PLAYER_GAMES: RefCell<StableBTreeMap<(Principal, GameId), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    static NEXT_GAME_ID: RefCell<StableCell<GameId, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+            0,
+        )
+        .expect("failed to initialize game id counter")
+    );
+
+    // Id of each in-progress game, by game hash.
+    static GAMES_BY_HASH: RefCell<StableBTreeMap<String, GameId, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    // Version of the layout the stored games were last written in.
+    static GAME_SCHEMA: RefCell<StableCell<u8, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+            0,
+        )
+        .expect("failed to initialize game schema version")
+    );
+
+    // Finished games keyed by (player, sequence). Entries are never removed, so the
+    // map length doubles as the next sequence number.
+    static HISTORY: RefCell<StableBTreeMap<(Principal, u64), GameRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
+
+    // Key in `HISTORY` of each finished game, by game hash.
+    static HISTORY_BY_HASH: RefCell<StableBTreeMap<String, (Principal, u64), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    // Open games, exposure and referral rewards owed on each ledger.
+    static OPEN_TOTALS: RefCell<StableBTreeMap<Principal, OpenTotals, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+
+    static STATS_REBUILD: RefCell<StableCell<StatsRebuild, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+            StatsRebuild::default(),
+        )
+        .expect("failed to initialize stats rebuild")
+    );
+
+    static CONFIG: RefCell<StableCell<Config, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            Config::default(),
+        )
+        .expect("failed to initialize config")
+    );
+
+    static TOTALS: RefCell<StableBTreeMap<Principal, HouseTotals, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    static PLAYERS: RefCell<StableBTreeMap<Principal, PlayerActivity, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    // Keyed by sequence number; entries are never removed.
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
+        )
+    );
+
+    static STATS: RefCell<StableBTreeMap<Principal, GameStats, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+
+    // Keyed by (ledger, player).
+    static PLAYER_STATS: RefCell<StableBTreeMap<(Principal, Principal), PlayerStats, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    // Players of each ledger ordered by net profit.
+    static LEADERBOARD: RefCell<StableBTreeMap<RankKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    static JACKPOTS: RefCell<StableBTreeMap<Principal, Jackpot, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+
+    // Tokens accepted besides the default ledger of the config, keyed by ledger.
+    static TOKENS: RefCell<StableBTreeMap<Principal, TokenConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+
+    // Referrer of each referred player. Set on the player's first game and never changed.
+    static REFERRERS: RefCell<StableBTreeMap<Principal, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    // Keyed by (referrer, ledger).
+    static REFERRAL_REWARDS: RefCell<StableBTreeMap<(Principal, Principal), ReferralRewards, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
 }
 
-pub fn get(key: Principal) -> Option<GameInfo> {
+pub fn get(key: GameId) -> Option<GameInfo> {
     MAP.with(|p| p.borrow().get(&key))
 }
 
-pub fn insert(key: Principal, value: GameInfo) {
-    MAP.with(|p| p.borrow_mut().insert(key, value));
+/// Stores a new game under the next game id and returns the id.
+pub fn create(value: GameInfo) -> GameId {
+    let key = NEXT_GAME_ID.with(|n| {
+        let key = *n.borrow().get();
+        n.borrow_mut()
+            .set(key + 1)
+            .expect("failed to save game id counter");
+        key
+    });
+    PLAYER_GAMES.with(|p| p.borrow_mut().insert((value.player(), key), ()));
+    GAMES_BY_HASH.with(|g| g.borrow_mut().insert(value.hash.clone(), key));
+    insert(key, value);
+    key
+}
+
+/// Saves a game previously stored with `create`.
+pub fn insert(key: GameId, value: GameInfo) {
+    let (ledger, liability) = (value.ledger(), value.liability());
+    let previous = MAP.with(|p| p.borrow_mut().insert(key, value));
+    if let Some(previous) = &previous {
+        update_open_totals(previous.ledger(), |totals| {
+            totals.exposure = totals.exposure.saturating_sub(previous.liability());
+        });
+    }
+    update_open_totals(ledger, |totals| {
+        totals.games += previous.is_none() as u64;
+        totals.exposure = totals.exposure.saturating_add(liability);
+    });
+}
+
+pub fn delete(key: GameId) -> Option<GameInfo> {
+    let value = MAP.with(|p| p.borrow_mut().remove(&key))?;
+    PLAYER_GAMES.with(|p| p.borrow_mut().remove(&(value.player(), key)));
+    GAMES_BY_HASH.with(|g| g.borrow_mut().remove(&value.hash));
+    update_open_totals(value.ledger(), |totals| {
+        totals.games = totals.games.saturating_sub(1);
+        totals.exposure = totals.exposure.saturating_sub(value.liability());
+    });
+    Some(value)
+}
+
+fn get_open_totals(ledger: Principal) -> OpenTotals {
+    OPEN_TOTALS.with(|t| t.borrow().get(&ledger).unwrap_or_default())
+}
+
+fn update_open_totals(ledger: Principal, f: impl FnOnce(&mut OpenTotals)) {
+    OPEN_TOTALS.with(|t| {
+        let mut map = t.borrow_mut();
+        let mut totals = map.get(&ledger).unwrap_or_default();
+        f(&mut totals);
+        map.insert(ledger, totals);
+    });
+}
+
+/// Returns the in-progress games of `player`, oldest first.
+pub fn player_games(player: Principal) -> Vec<(GameId, GameInfo)> {
+    let keys: Vec<GameId> = PLAYER_GAMES.with(|p| {
+        p.borrow()
+            .range((player, 0)..=(player, GameId::MAX))
+            .map(|((_, key), _)| key)
+            .collect()
+    });
+    keys.into_iter()
+        .filter_map(|key| get(key).map(|value| (key, value)))
+        .collect()
+}
+
+/// Moves the games of the legacy principal-keyed map to the id-keyed map, oldest first.
+/// Returns the number of games moved.
+pub fn migrate_legacy_games() -> u64 {
+    let mut legacy: Vec<(Principal, LegacyGameInfo)> =
+        LEGACY_MAP.with(|l| l.borrow().iter().collect());
+    legacy.sort_by_key(|(_, info)| info.timestamp_nanos);
+
+    for (player, info) in legacy.iter() {
+        // The legacy layout is a bare v1 record, which `GameInfo` decodes as a `Started` game.
+        let mut info = GameInfo::from_bytes(info.to_bytes());
+        info.player = Some(*player);
+        create(info);
+        LEGACY_MAP.with(|l| l.borrow_mut().remove(player));
+    }
+    legacy.len() as u64
+}
+
+/// Number of in-progress games on `ledger`.
+pub fn open_games(ledger: Principal) -> u64 {
+    get_open_totals(ledger).games
+}
+
+/// Sum of the payouts owed on `ledger` if every in-progress game were won.
+pub fn open_exposure(ledger: Principal) -> u64 {
+    get_open_totals(ledger).exposure
+}
+
+/// Counts the open games and referral rewards into the per-ledger totals if an older release,
+/// which did not keep them, left any behind. Returns the number of ledgers counted.
+pub fn seed_open_totals() -> u64 {
+    if OPEN_TOTALS.with(|t| !t.borrow().is_empty()) {
+        return 0;
+    }
+
+    let mut totals: BTreeMap<Principal, OpenTotals> = BTreeMap::new();
+    MAP.with(|p| {
+        for (_, info) in p.borrow().iter() {
+            let entry = totals.entry(info.ledger()).or_default();
+            entry.games += 1;
+            entry.exposure = entry.exposure.saturating_add(info.liability());
+        }
+    });
+    REFERRAL_REWARDS.with(|r| {
+        for ((_, ledger), rewards) in r.borrow().iter() {
+            let entry = totals.entry(ledger).or_default();
+            entry.referral_rewards = entry.referral_rewards.saturating_add(rewards.owed());
+        }
+    });
+    let ledgers = totals.len() as u64;
+    OPEN_TOTALS.with(|t| {
+        let mut map = t.borrow_mut();
+        for (ledger, entry) in totals {
+            map.insert(ledger, entry);
+        }
+    });
+    ledgers
+}
+
+/// Rewrites the stored games in the current layout if an older release wrote them.
+/// Returns the number of games rewritten.
+pub fn migrate_game_schema() -> u64 {
+    if GAME_SCHEMA.with(|v| *v.borrow().get()) >= GAME_INFO_VERSION {
+        return 0;
+    }
+
+    let games: Vec<(GameId, GameInfo)> = MAP.with(|p| p.borrow().iter().collect());
+    for (key, info) in games.iter().cloned() {
+        insert(key, info);
+    }
+    GAME_SCHEMA.with(|v| {
+        v.borrow_mut()
+            .set(GAME_INFO_VERSION)
+            .expect("failed to save game schema version")
+    });
+    games.len() as u64
+}
+
+/// Returns the in-progress games accepted by `filter`, oldest first.
+pub fn list_games(
+    filter: impl Fn(&GameInfo) -> bool,
+    offset: usize,
+    limit: usize,
+) -> Vec<(GameId, GameInfo)> {
+    MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, info)| filter(info))
+            .skip(offset)
+            .take(limit)
+            .collect()
+    })
+}
+
+pub fn find_game(hash: &str) -> Option<(GameId, GameInfo)> {
+    let key = GAMES_BY_HASH.with(|g| g.borrow().get(&hash.to_string()))?;
+    get(key).map(|value| (key, value))
+}
+
+/// Returns the games that were started but not played within `expiry_nanos`.
+pub fn expired_games(now: u64, expiry_nanos: u64) -> Vec<(GameId, GameInfo)> {
+    MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, info)| {
+                info.state() == GameState::Started
+                    && now.saturating_sub(info.timestamp_nanos) > expiry_nanos
+            })
+            .collect()
+    })
+}
+
+pub fn pending_payouts() -> Vec<(GameId, GameInfo)> {
+    MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, info)| matches!(info.state(), GameState::PayoutPending { .. }))
+            .collect()
+    })
+}
+
+/// Removes the in-progress game `key` and appends its final record to the history.
+pub fn archive(key: GameId, record: GameRecord) {
+    delete(key);
+    HISTORY.with(|h| {
+        let mut h = h.borrow_mut();
+        let key = (record.player, h.len());
+        // A running rebuild counts the records past its position when it reaches them.
+        let rebuild = STATS_REBUILD.with(|r| r.borrow().get().clone());
+        if !rebuild.running || rebuild.done.is_some_and(|done| key <= done) {
+            add_stats(&record);
+        }
+        HISTORY_BY_HASH.with(|i| i.borrow_mut().insert(record.hash.clone(), key));
+        h.insert(key, record);
+    });
+}
+
+/// Returns finished games of `player`, newest first.
+pub fn player_history(player: Principal, offset: usize, limit: usize) -> Vec<GameRecord> {
+    HISTORY.with(|h| {
+        h.borrow()
+            .range((player, 0)..=(player, u64::MAX))
+            .rev()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+/// Returns finished games grouped by player, each player's games in the order they finished.
+pub fn history(offset: usize, limit: usize) -> Vec<GameRecord> {
+    HISTORY.with(|h| {
+        h.borrow()
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(_, record)| record)
+            .collect()
+    })
+}
+
+pub fn find_record(hash: &str) -> Option<GameRecord> {
+    let key = HISTORY_BY_HASH.with(|i| i.borrow().get(&hash.to_string()))?;
+    HISTORY.with(|h| h.borrow().get(&key))
+}
+
+pub fn get_config() -> Config {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+pub fn set_config(config: Config) {
+    CONFIG.with(|c| c.borrow_mut().set(config).expect("failed to save config"));
+}
+
+/// Returns the settings of `ledger` if bets are accepted on it.
+pub fn get_token(ledger: Principal) -> Option<TokenConfig> {
+    let config = get_config();
+    if ledger == config.ledger_canister_id {
+        return Some(config.default_token());
+    }
+    TOKENS.with(|t| t.borrow().get(&ledger))
 }
 
-pub fn delete(key: Principal) -> Option<GameInfo> {
-    MAP.with(|p| p.borrow_mut().remove(&key))
+/// Returns every accepted token, the default ledger first.
+pub fn tokens() -> Vec<(Principal, TokenConfig)> {
+    let config = get_config();
+    let mut tokens = vec![(config.ledger_canister_id, config.default_token())];
+    TOKENS.with(|t| tokens.extend(t.borrow().iter()));
+    tokens
+}
+
+pub fn set_token(ledger: Principal, token: TokenConfig) {
+    TOKENS.with(|t| t.borrow_mut().insert(ledger, token));
+}
+
+pub fn remove_token(ledger: Principal) -> Option<TokenConfig> {
+    TOKENS.with(|t| t.borrow_mut().remove(&ledger))
+}
+
+pub fn get_totals(ledger: Principal) -> HouseTotals {
+    TOTALS.with(|t| t.borrow().get(&ledger).unwrap_or_default())
+}
+
+pub fn add_wagered(ledger: Principal, amount: u64) {
+    let mut totals = get_totals(ledger);
+    totals.total_wagered = totals.total_wagered.saturating_add(amount);
+    TOTALS.with(|t| t.borrow_mut().insert(ledger, totals));
+}
+
+pub fn add_paid_out(ledger: Principal, amount: u64) {
+    let mut totals = get_totals(ledger);
+    totals.total_paid_out = totals.total_paid_out.saturating_add(amount);
+    TOTALS.with(|t| t.borrow_mut().insert(ledger, totals));
+}
+
+pub fn get_jackpot(ledger: Principal) -> Jackpot {
+    JACKPOTS.with(|j| j.borrow().get(&ledger).unwrap_or_default())
+}
+
+fn update_jackpot(ledger: Principal, f: impl FnOnce(&mut Jackpot)) {
+    let mut jackpot = get_jackpot(ledger);
+    f(&mut jackpot);
+    JACKPOTS.with(|j| j.borrow_mut().insert(ledger, jackpot));
+}
+
+pub fn add_to_jackpot(ledger: Principal, amount: u64) {
+    update_jackpot(ledger, |jackpot| {
+        jackpot.pool = jackpot.pool.saturating_add(amount);
+        jackpot.total_contributed = jackpot.total_contributed.saturating_add(amount);
+    });
+}
+
+/// Empties the pool of `ledger` in favour of `player` and returns the amount won.
+pub fn take_jackpot(ledger: Principal, player: Principal, game_id: GameId, now: u64) -> u64 {
+    let mut amount = 0;
+    update_jackpot(ledger, |jackpot| {
+        amount = std::mem::take(&mut jackpot.pool);
+        jackpot.total_paid_out = jackpot.total_paid_out.saturating_add(amount);
+        jackpot.wins += 1;
+        jackpot.last_win = Some(JackpotWin {
+            player,
+            game_id,
+            amount,
+            timestamp_nanos: now,
+        });
+    });
+    amount
+}
+
+pub fn get_referrer(player: Principal) -> Option<Principal> {
+    REFERRERS.with(|r| r.borrow().get(&player))
+}
+
+/// Binds `player` to `referrer` unless the player already has a referrer.
+pub fn set_referrer(player: Principal, referrer: Principal) {
+    REFERRERS.with(|r| {
+        let mut referrers = r.borrow_mut();
+        if !referrers.contains_key(&player) {
+            referrers.insert(player, referrer);
+        }
+    });
+}
+
+/// Returns the rewards of `referrer` in every token they earned in.
+pub fn referral_rewards(referrer: Principal) -> Vec<(Principal, ReferralRewards)> {
+    REFERRAL_REWARDS.with(|r| {
+        r.borrow()
+            .range((referrer, Principal::management_canister())..)
+            .take_while(|((key, _), _)| *key == referrer)
+            .map(|((_, ledger), rewards)| (ledger, rewards))
+            .collect()
+    })
+}
+
+/// Total rewards on `ledger` owed to the referrers.
+pub fn referral_rewards_owed(ledger: Principal) -> u64 {
+    get_open_totals(ledger).referral_rewards
+}
+
+fn update_referral_rewards(
+    referrer: Principal,
+    ledger: Principal,
+    f: impl FnOnce(&mut ReferralRewards),
+) {
+    REFERRAL_REWARDS.with(|r| {
+        let mut map = r.borrow_mut();
+        let mut rewards = map.get(&(referrer, ledger)).unwrap_or_default();
+        let owed = rewards.owed();
+        f(&mut rewards);
+        let change = rewards.owed() as i128 - owed as i128;
+        map.insert((referrer, ledger), rewards);
+        update_open_totals(ledger, |totals| {
+            totals.referral_rewards = (totals.referral_rewards as i128 + change).max(0) as u64;
+        });
+    });
+}
+
+pub fn add_referral_reward(referrer: Principal, ledger: Principal, amount: u64) {
+    update_referral_rewards(referrer, ledger, |rewards| {
+        rewards.claimable = rewards.claimable.saturating_add(amount);
+        rewards.total_earned = rewards.total_earned.saturating_add(amount);
+    });
+}
+
+/// Returns the pending claim of `referrer` on `ledger`, or moves the claimable rewards to a new
+/// claim created at `now` if they cover `fee`. Returns the claimable rewards when they do not.
+pub fn open_referral_claim(
+    referrer: Principal,
+    ledger: Principal,
+    fee: u64,
+    now: u64,
+) -> Result<ReferralClaim, u64> {
+    let mut result = Err(0);
+    update_referral_rewards(referrer, ledger, |rewards| {
+        if let Some(claim) = &rewards.pending_claim {
+            result = Ok(claim.clone());
+            return;
+        }
+        if rewards.claimable <= fee {
+            result = Err(rewards.claimable);
+            return;
+        }
+
+        let claim = ReferralClaim {
+            id: rewards.claims,
+            amount: std::mem::take(&mut rewards.claimable),
+            fee,
+            created_at_time: now,
+        };
+        rewards.claims += 1;
+        rewards.total_claimed = rewards.total_claimed.saturating_add(claim.amount);
+        rewards.pending_claim = Some(claim.clone());
+        result = Ok(claim);
+    });
+    result
+}
+
+pub fn pending_referral_claim(referrer: Principal, ledger: Principal) -> Option<ReferralClaim> {
+    REFERRAL_REWARDS.with(|r| {
+        r.borrow()
+            .get(&(referrer, ledger))
+            .and_then(|rewards| rewards.pending_claim)
+    })
+}
+
+/// Sets a new `created_at_time` on the pending claim of `referrer` on `ledger`.
+pub fn refresh_referral_claim(referrer: Principal, ledger: Principal, now: u64) {
+    update_referral_rewards(referrer, ledger, |rewards| {
+        if let Some(claim) = &mut rewards.pending_claim {
+            claim.created_at_time = now;
+        }
+    });
+}
+
+/// Ends the claim `id` of `referrer` on `ledger` if it is still pending. The rewards go back to
+/// `claimable` unless the ledger took the transfer.
+pub fn close_referral_claim(referrer: Principal, ledger: Principal, id: u64, paid: bool) {
+    update_referral_rewards(referrer, ledger, |rewards| {
+        let Some(claim) = rewards.pending_claim.take_if(|claim| claim.id == id) else {
+            return;
+        };
+        if !paid {
+            rewards.claimable = rewards.claimable.saturating_add(claim.amount);
+            rewards.total_claimed = rewards.total_claimed.saturating_sub(claim.amount);
+        }
+    });
+}
+
+pub fn get_player(key: Principal) -> PlayerActivity {
+    PLAYERS.with(|p| p.borrow().get(&key).unwrap_or_default())
+}
+
+pub fn set_player(key: Principal, activity: PlayerActivity) {
+    PLAYERS.with(|p| p.borrow_mut().insert(key, activity));
+}
+
+pub fn append_audit(entry: AuditEntry) {
+    AUDIT_LOG.with(|a| {
+        let mut a = a.borrow_mut();
+        let sequence = a.len();
+        a.insert(sequence, entry);
+    });
+}
+
+/// Returns audit log entries, newest first.
+pub fn audit_log(offset: usize, limit: usize) -> Vec<AuditEntry> {
+    AUDIT_LOG.with(|a| {
+        let a = a.borrow();
+        let end = a.len().saturating_sub(offset as u64);
+        let start = end.saturating_sub(limit as u64);
+        let mut entries: Vec<AuditEntry> = a.range(start..end).map(|(_, entry)| entry).collect();
+        entries.reverse();
+        entries
+    })
+}
+
+fn add_stats(record: &GameRecord) {
+    let ledger = record
+        .ledger_canister_id
+        .unwrap_or_else(|| get_config().ledger_canister_id);
+    let mut stats = get_stats(ledger);
+    stats.add(record);
+    STATS.with(|s| s.borrow_mut().insert(ledger, stats));
+
+    let key = (ledger, record.player);
+    let mut player = PLAYER_STATS.with(|p| p.borrow().get(&key).unwrap_or_default());
+    LEADERBOARD.with(|l| {
+        l.borrow_mut()
+            .remove(&(ledger, (player.rank_key(), record.player)))
+    });
+    player.games += 1;
+    player.wagered = player.wagered.saturating_add(record.amount);
+    player.paid_out = player.paid_out.saturating_add(record.payout);
+    match record.outcome {
+        GameOutcome::Win => player.wins += 1,
+        GameOutcome::Loss => player.losses += 1,
+        GameOutcome::Refunded | GameOutcome::Forfeited => {}
+    }
+    LEADERBOARD.with(|l| {
+        l.borrow_mut()
+            .insert((ledger, (player.rank_key(), record.player)), ())
+    });
+    PLAYER_STATS.with(|p| p.borrow_mut().insert(key, player));
+}
+
+pub fn get_stats(ledger: Principal) -> GameStats {
+    STATS.with(|s| s.borrow().get(&ledger).unwrap_or_default())
+}
+
+/// Returns the players with the highest net profit on `ledger`, best first.
+pub fn leaderboard(ledger: Principal, limit: usize) -> Vec<(Principal, PlayerStats)> {
+    let players: Vec<Principal> = LEADERBOARD.with(|l| {
+        l.borrow()
+            .range((ledger, (0, Principal::management_canister()))..)
+            .take_while(|((key, _), _)| *key == ledger)
+            .take(limit)
+            .map(|((_, (_, player)), _)| player)
+            .collect()
+    });
+    players
+        .into_iter()
+        .map(|player| {
+            let stats =
+                PLAYER_STATS.with(|p| p.borrow().get(&(ledger, player)).unwrap_or_default());
+            (player, stats)
+        })
+        .collect()
+}
+
+/// Starts recomputing the statistics from the history if they do not cover every finished
+/// game, e.g. after upgrading from a release without statistics. The records are counted by
+/// `rebuild_stats_batch`. Returns whether a rebuild is running.
+pub fn start_stats_rebuild() -> bool {
+    if STATS_REBUILD.with(|r| r.borrow().get().running) {
+        return true;
+    }
+    let games: u64 = STATS.with(|s| s.borrow().iter().map(|(_, stats)| stats.games).sum());
+    if games == HISTORY.with(|h| h.borrow().len()) {
+        return false;
+    }
+
+    STATS.with(|s| s.borrow_mut().clear_new());
+    PLAYER_STATS.with(|p| p.borrow_mut().clear_new());
+    LEADERBOARD.with(|l| l.borrow_mut().clear_new());
+    set_stats_rebuild(StatsRebuild {
+        running: true,
+        done: None,
+    });
+    true
+}
+
+/// Counts up to `limit` history records into the statistics of a running rebuild.
+/// Returns whether records are left.
+pub fn rebuild_stats_batch(limit: usize) -> bool {
+    let rebuild = STATS_REBUILD.with(|r| r.borrow().get().clone());
+    if !rebuild.running {
+        return false;
+    }
+
+    let start = match rebuild.done {
+        Some(done) => std::ops::Bound::Excluded(done),
+        None => std::ops::Bound::Unbounded,
+    };
+    let records: Vec<((Principal, u64), GameRecord)> = HISTORY.with(|h| {
+        h.borrow()
+            .range((start, std::ops::Bound::Unbounded))
+            .take(limit)
+            .collect()
+    });
+    for (_, record) in &records {
+        add_stats(record);
+    }
+    let more = records.len() == limit;
+    set_stats_rebuild(StatsRebuild {
+        running: more,
+        done: records.last().map(|(key, _)| *key).or(rebuild.done),
+    });
+    more
+}
+
+fn set_stats_rebuild(rebuild: StatsRebuild) {
+    STATS_REBUILD.with(|r| {
+        r.borrow_mut()
+            .set(rebuild)
+            .expect("failed to save stats rebuild")
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    /// `GameInfo` as stored by the first release.
+    #[derive(CandidType, Deserialize)]
+    struct GameInfoV1 {
+        amount: u64,
+        timestamp_nanos: u64,
+        result: String,
+        random_hex: String,
+        hash: String,
+    }
+
+    /// A game stored by the first release: `Encode!` of its `GameInfo` with amount
+    /// 100_000_000, timestamp 1_700_000_000_123_456_789 and result `Even`.
+    const V1_GAME_INFO_HEX: &str = "4449444c016c05ddd1914471d6f68e800178cedfa0a80471dfced9a00c71d8a38ca80d780100044576656e15cd853dfe9c97174063363531323532666133356536616237326463633061626339636534656133366362313432643734303566623861333566633834376230313564356439326335403361376339653162336137633965316233613763396531623361376339653162336137633965316233613763396531623361376339653162336137633965316200e1f50500000000";
+
+    fn game_info() -> GameInfo {
+        GameInfo {
+            player: Some(Principal::anonymous()),
+            amount: 100_000_000,
+            timestamp_nanos: 1_700_000_000_000_000_000,
+            result: None,
+            random_hex: "ab".repeat(32),
+            hash: "cd".repeat(32),
+            state: Some(GameState::PayoutPending {
+                amount: 200_000_000,
+                created_at_time: 1_700_000_000_000_000_001,
+                refund: false,
+            }),
+            guess: Some("Odd".to_string()),
+            game_type: Some(GameType::Dice),
+            client_seed: Some("seed".to_string()),
+            jackpot_odds: Some(10_000),
+            jackpot_won: None,
+            ledger_canister_id: Some(Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()),
+        }
+    }
+
+    fn assert_same(left: &GameInfo, right: &GameInfo) {
+        assert_eq!(left.player, right.player);
+        assert_eq!(left.amount, right.amount);
+        assert_eq!(left.timestamp_nanos, right.timestamp_nanos);
+        assert_eq!(left.result, right.result);
+        assert_eq!(left.random_hex, right.random_hex);
+        assert_eq!(left.hash, right.hash);
+        assert!(left.state == right.state);
+        assert_eq!(left.guess, right.guess);
+        assert_eq!(left.game_type, right.game_type);
+        assert_eq!(left.client_seed, right.client_seed);
+        assert_eq!(left.jackpot_odds, right.jackpot_odds);
+        assert_eq!(left.jackpot_won, right.jackpot_won);
+        assert_eq!(left.ledger_canister_id, right.ledger_canister_id);
+    }
+
+    #[test]
+    fn v1_record_migrates_into_current_layout() {
+        let v1 = GameInfoV1 {
+            amount: 50_000_000,
+            timestamp_nanos: 1_600_000_000_000_000_000,
+            result: "Even".to_string(),
+            random_hex: "01".repeat(32),
+            hash: "02".repeat(32),
+        };
+
+        let info = GameInfo::from_bytes(Cow::Owned(Encode!(&v1).unwrap()));
+
+        assert_eq!(info.player, None);
+        assert_eq!(info.amount, v1.amount);
+        assert_eq!(info.timestamp_nanos, v1.timestamp_nanos);
+        assert_eq!(info.result, Some(v1.result));
+        assert_eq!(info.random_hex, v1.random_hex);
+        assert_eq!(info.hash, v1.hash);
+        assert!(info.state == Some(GameState::Started));
+        assert_eq!(info.guess, None);
+        assert_eq!(info.game_type, Some(GameType::OddEven));
+        assert_eq!(info.client_seed, None);
+    }
+
+    #[test]
+    fn first_release_bytes_migrate_into_current_layout() {
+        let bytes = hex::decode(V1_GAME_INFO_HEX).unwrap();
+        let random_hex = "3a7c9e1b".repeat(8);
+
+        let legacy = LegacyGameInfo::from_bytes(Cow::Borrowed(&bytes));
+        assert_eq!(legacy.to_bytes().as_ref(), bytes.as_slice());
+        let info = GameInfo::from_bytes(Cow::Owned(bytes));
+
+        assert_eq!(info.player, None);
+        assert_eq!(info.amount, 100_000_000);
+        assert_eq!(info.timestamp_nanos, 1_700_000_000_123_456_789);
+        assert_eq!(info.result.as_deref(), Some("Even"));
+        assert_eq!(info.random_hex, random_hex);
+        assert_eq!(
+            info.hash,
+            hex::encode(Sha256::digest(format!("Even|{random_hex}")))
+        );
+        assert!(info.state == Some(GameState::Started));
+        assert_eq!(info.game_type, Some(GameType::OddEven));
+    }
+
+    #[test]
+    fn migrated_v1_record_round_trips_in_envelope() {
+        let v1 = GameInfoV1 {
+            amount: 50_000_000,
+            timestamp_nanos: 1_600_000_000_000_000_000,
+            result: "Odd".to_string(),
+            random_hex: "03".repeat(32),
+            hash: "04".repeat(32),
+        };
+        let migrated = GameInfo::from_bytes(Cow::Owned(Encode!(&v1).unwrap()));
+
+        let bytes = migrated.to_bytes();
+        assert!(matches!(
+            Decode!(bytes.as_ref(), VersionedGameInfo),
+            Ok(VersionedGameInfo::V2(_))
+        ));
+        assert_same(&GameInfo::from_bytes(bytes), &migrated);
+    }
+
+    #[test]
+    fn bare_record_keeps_optional_fields() {
+        let info = game_info();
+
+        let decoded = GameInfo::from_bytes(Cow::Owned(Encode!(&info).unwrap()));
+
+        assert_same(&decoded, &info);
+    }
+
+    #[test]
+    fn current_layout_round_trips() {
+        let info = game_info();
+
+        assert_same(&GameInfo::from_bytes(info.to_bytes()), &info);
+    }
+
+    #[test]
+    fn player_history_pages_newest_first() {
+        let player = Principal::anonymous();
+        let other = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        for (game_id, owner) in [(0, player), (1, other), (2, player), (3, player)] {
+            let info = GameInfo {
+                player: Some(owner),
+                ..game_info()
+            };
+            archive(game_id, info.into_record(game_id, game_id));
+        }
+
+        let ids = |records: Vec<GameRecord>| -> Vec<Option<GameId>> {
+            records.into_iter().map(|record| record.game_id).collect()
+        };
+        assert_eq!(
+            ids(player_history(player, 0, 10)),
+            [Some(3), Some(2), Some(0)]
+        );
+        assert_eq!(ids(player_history(player, 1, 1)), [Some(2)]);
+        assert_eq!(ids(player_history(player, 3, 10)), []);
+        assert_eq!(ids(player_history(other, 0, 10)), [Some(1)]);
+    }
+
+    #[test]
+    fn games_and_records_are_found_by_hash() {
+        let hash = |n: u8| format!("{n:02x}").repeat(32);
+        let game_id = create(GameInfo {
+            hash: hash(1),
+            ..game_info()
+        });
+        let archived = create(GameInfo {
+            hash: hash(2),
+            ..game_info()
+        });
+        let info = delete(archived).unwrap();
+        archive(archived, info.into_record(archived, 0));
+
+        assert_eq!(find_game(&hash(1)).map(|(key, _)| key), Some(game_id));
+        assert!(find_game(&hash(2)).is_none());
+        assert_eq!(
+            find_record(&hash(2)).and_then(|r| r.game_id),
+            Some(archived)
+        );
+        assert!(find_record(&hash(1)).is_none());
+        assert!(find_record(&hash(3)).is_none());
+    }
+
+    #[test]
+    fn legacy_games_move_to_the_id_keyed_map_oldest_first() {
+        let older = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let newer = Principal::anonymous();
+        for (player, timestamp_nanos, hash) in [(newer, 2, "02"), (older, 1, "01")] {
+            let info = LegacyGameInfo {
+                amount: 100_000_000,
+                timestamp_nanos,
+                result: "even".to_string(),
+                random_hex: "ab".repeat(32),
+                hash: hash.repeat(32),
+            };
+            LEGACY_MAP.with(|l| l.borrow_mut().insert(player, info));
+        }
+
+        assert_eq!(migrate_legacy_games(), 2);
+
+        assert!(LEGACY_MAP.with(|l| l.borrow().is_empty()));
+        let games = list_games(|_| true, 0, 10);
+        let players: Vec<_> = games.iter().map(|(_, info)| info.player()).collect();
+        assert_eq!(players, [older, newer]);
+        let (_, first) = &games[0];
+        assert!(matches!(first.state, Some(GameState::Started)));
+        assert_eq!(first.result.as_deref(), Some("even"));
+        assert_eq!(first.hash, "01".repeat(32));
+        assert_eq!(migrate_legacy_games(), 0);
+    }
+
+    #[test]
+    fn stats_rebuild_counts_each_record_once() {
+        let ledger = game_info().ledger();
+        let first = Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai").unwrap();
+        let middle = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let last = Principal::anonymous();
+        let finish = |game_id: GameId, player: Principal| {
+            let info = GameInfo {
+                player: Some(player),
+                ..game_info()
+            };
+            archive(game_id, info.into_record(game_id, 0));
+        };
+        for (game_id, player) in [middle, last, middle, last].into_iter().enumerate() {
+            finish(game_id as GameId, player);
+        }
+        STATS.with(|s| s.borrow_mut().clear_new());
+        assert!(start_stats_rebuild());
+
+        // The batch counts both records of `middle`. Games finished meanwhile are counted
+        // right away before that position and by the next batches after it.
+        assert!(rebuild_stats_batch(2));
+        finish(4, first);
+        finish(5, middle);
+        finish(6, last);
+        while rebuild_stats_batch(2) {}
+
+        assert_eq!(get_stats(ledger).games, 7);
+        assert!(!start_stats_rebuild());
+    }
+
+    #[test]
+    fn open_totals_follow_games_and_rewards() {
+        let ledger = game_info().ledger();
+        let referrer = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let first = create(game_info());
+        let second = create(GameInfo {
+            hash: "ef".repeat(32),
+            ..game_info()
+        });
+        assert_eq!(open_games(ledger), 2);
+        assert_eq!(open_exposure(ledger), 2 * game_info().liability());
+
+        let settled = GameInfo {
+            state: Some(GameState::Revealed),
+            ..game_info()
+        };
+        insert(first, settled.clone());
+        assert_eq!(open_games(ledger), 2);
+        assert_eq!(
+            open_exposure(ledger),
+            game_info().liability() + settled.liability()
+        );
+
+        let info = delete(second).unwrap();
+        archive(second, info.into_record(second, 0));
+        assert_eq!(open_games(ledger), 1);
+        assert_eq!(open_exposure(ledger), settled.liability());
+
+        update_referral_rewards(referrer, ledger, |rewards| rewards.claimable += 500);
+        update_referral_rewards(referrer, ledger, |rewards| rewards.claimable -= 200);
+        assert_eq!(referral_rewards_owed(ledger), 300);
+
+        OPEN_TOTALS.with(|t| t.borrow_mut().remove(&ledger));
+        assert_eq!(seed_open_totals(), 1);
+        assert_eq!(open_games(ledger), 1);
+        assert_eq!(open_exposure(ledger), settled.liability());
+        assert_eq!(referral_rewards_owed(ledger), 300);
+    }
 }
@@ -0,0 +1,133 @@
+//! Destinations of a transfer, as users write them.
+
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::icp_ledger::AccountIdentifier;
+
+pub enum Destination {
+    Account(Account),
+    /// Only the ICP ledger accepts these.
+    AccountIdentifier(AccountIdentifier),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Account(account) => write!(f, "{account}"),
+            Destination::AccountIdentifier(id) => write!(f, "{id}"),
+        }
+    }
+}
+
+/// Parses a subaccount given in hex. Leading zeros may be left out, as in the ICRC-1 textual
+/// encoding of accounts.
+pub fn parse_subaccount(text: &str) -> Result<Subaccount, String> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    if digits.is_empty() || digits.len() > 64 {
+        return Err(format!("subaccount {text} must be 1 to 64 hex characters"));
+    }
+
+    hex::decode(format!("{digits:0>64}"))
+        .map_err(|err| format!("invalid subaccount {text}: {err}"))?
+        .try_into()
+        .map_err(|_| format!("invalid subaccount {text}"))
+}
+
+/// Parses `to`, which is one of:
+/// - a principal, optionally with a hex `subaccount`;
+/// - an ICRC-1 account in textual form (`principal-checksum.subaccount`);
+/// - a legacy account identifier of 64 hex characters.
+pub fn parse_destination(to: &str, subaccount: Option<&str>) -> Result<Destination, String> {
+    let to = to.trim();
+
+    if to.len() == 64 && to.chars().all(|c| c.is_ascii_hexdigit()) {
+        if subaccount.is_some() {
+            return Err(
+                "a subaccount can not be given with an account identifier, which already includes one"
+                    .to_string(),
+            );
+        }
+        return AccountIdentifier::from_hex(to).map(Destination::AccountIdentifier);
+    }
+
+    let mut account =
+        Account::from_str(to).map_err(|err| format!("invalid destination {to}: {err}"))?;
+
+    if let Some(subaccount) = subaccount {
+        if account.subaccount.is_some() {
+            return Err(format!(
+                "{to} already includes a subaccount; do not give one separately"
+            ));
+        }
+        account.subaccount = Some(parse_subaccount(subaccount.trim())?);
+    }
+
+    Ok(Destination::Account(account))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::icp_ledger::account_identifier_hex;
+    use candid::Principal;
+
+    const OWNER: &str = "rdmx6-jaaaa-aaaaa-aaadq-cai";
+
+    fn account(destination: Destination) -> Account {
+        match destination {
+            Destination::Account(account) => account,
+            Destination::AccountIdentifier(_) => panic!("expected an account"),
+        }
+    }
+
+    #[test]
+    fn parses_principal() {
+        let account = account(parse_destination(OWNER, None).unwrap());
+        assert_eq!(account.owner, Principal::from_text(OWNER).unwrap());
+        assert_eq!(account.subaccount, None);
+    }
+
+    #[test]
+    fn parses_principal_with_subaccount() {
+        let account = account(parse_destination(OWNER, Some("0x01ff")).unwrap());
+        let mut expected = [0; 32];
+        expected[30] = 0x01;
+        expected[31] = 0xff;
+        assert_eq!(account.subaccount, Some(expected));
+    }
+
+    #[test]
+    fn parses_full_subaccount() {
+        assert_eq!(parse_subaccount(&"ab".repeat(32)), Ok([0xab; 32]));
+    }
+
+    #[test]
+    fn rejects_invalid_subaccounts() {
+        for text in ["", "0x", "xyz", &"1".repeat(65)] {
+            assert!(parse_subaccount(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn routes_64_hex_characters_to_account_identifiers() {
+        let text = account_identifier_hex([3; 28]);
+        let destination = parse_destination(&text, None).unwrap();
+        assert!(
+            matches!(destination, Destination::AccountIdentifier(id) if id.to_string() == text)
+        );
+    }
+
+    #[test]
+    fn rejects_subaccount_with_account_identifier() {
+        let text = account_identifier_hex([3; 28]);
+        assert!(parse_destination(&text, Some("01")).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_destinations() {
+        assert!(parse_destination("not a principal", None).is_err());
+        assert!(parse_destination(&"0".repeat(64), None).is_err());
+    }
+}
@@ -0,0 +1,103 @@
+//! Conversions between amounts in the smallest unit of a token, as the ledgers count them, and
+//! the decimal notation users write, such as `1.5` ICP.
+
+use candid::Nat;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+/// Parses a positive decimal amount of a token with `decimals` decimals into its smallest
+/// unit. Amounts with more fractional digits than the token has are rejected, not rounded.
+pub fn parse_amount(amount: &str, decimals: u8) -> Result<Nat, String> {
+    let value = Decimal::from_str_exact(amount.trim())
+        .map_err(|err| format!("invalid amount {amount}: {err}"))?
+        .normalize();
+
+    if value.is_sign_negative() || value.is_zero() {
+        return Err(format!("amount must be positive, got {amount}"));
+    }
+    if value.scale() > decimals as u32 {
+        return Err(format!(
+            "amount {amount} has more than {decimals} decimal places"
+        ));
+    }
+
+    let zeros = "0".repeat((decimals as u32 - value.scale()) as usize);
+    Nat::from_str(&format!("{}{zeros}", value.mantissa()))
+        .map_err(|err| format!("invalid amount {amount}: {err:?}"))
+}
+
+/// Formats an amount in the smallest unit of a token with `decimals` decimals, without
+/// trailing zeros.
+pub fn format_amount(amount: &Nat, decimals: u8) -> String {
+    let decimals = decimals as usize;
+    let digits = format!("{:0>width$}", amount.0.to_string(), width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{whole}.{fraction}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_decimal_amounts() {
+        assert_eq!(parse_amount("1.5", 8), Ok(Nat::from(150_000_000u64)));
+        assert_eq!(parse_amount("0.00000001", 8), Ok(Nat::from(1u64)));
+        assert_eq!(parse_amount("2", 0), Ok(Nat::from(2u64)));
+        assert_eq!(
+            parse_amount(" 1.50000000 ", 8),
+            Ok(Nat::from(150_000_000u64))
+        );
+    }
+
+    #[test]
+    fn parses_amounts_beyond_u64() {
+        // 100 ckETH is more than u64::MAX wei.
+        assert_eq!(
+            parse_amount("100", 18),
+            Ok(Nat::from(100_000_000_000_000_000_000u128))
+        );
+        assert_eq!(
+            parse_amount("1234567.000000000000000001", 18),
+            Ok(Nat::from(1_234_567_000_000_000_000_000_001u128))
+        );
+    }
+
+    #[test]
+    fn rejects_over_precise_amounts() {
+        assert!(parse_amount("0.000000001", 8).is_err());
+        assert!(parse_amount("1.0000001", 6).is_err());
+        assert!(parse_amount("1.5", 0).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        for amount in ["", "abc", "1,5", "-1", "0", "0.000", "1e8"] {
+            assert!(parse_amount(amount, 8).is_err(), "{amount}");
+        }
+    }
+
+    #[test]
+    fn formats_amounts() {
+        assert_eq!(format_amount(&Nat::from(150_000_000u64), 8), "1.5");
+        assert_eq!(format_amount(&Nat::from(1u64), 8), "0.00000001");
+        assert_eq!(format_amount(&Nat::from(0u64), 8), "0");
+        assert_eq!(format_amount(&Nat::from(42u64), 0), "42");
+        assert_eq!(
+            format_amount(&Nat::from(100_000_000_000_000_000_000u128), 18),
+            "100"
+        );
+    }
+
+    #[test]
+    fn formatted_amounts_parse_back() {
+        let amount = Nat::from(1_234_567_000_000_000_000_000_001u128);
+        assert_eq!(parse_amount(&format_amount(&amount, 18), 18), Ok(amount));
+    }
+}
@@ -0,0 +1,119 @@
+//! The original `transfer` endpoint of the ICP ledger, which addresses accounts by a 32-byte
+//! account identifier instead of an ICRC-1 account. Exchanges still give these identifiers out
+//! for ICP deposits.
+
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::fmt;
+
+use crate::runtime::{CdkRuntime, Runtime};
+
+pub const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+/// A legacy account identifier: the big-endian CRC-32 of a 28-byte hash, then the hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccountIdentifier([u8; 32]);
+
+impl AccountIdentifier {
+    /// Parses the 64 hex characters form and checks its checksum.
+    pub fn from_hex(text: &str) -> Result<Self, String> {
+        let bytes: [u8; 32] = hex::decode(text)
+            .map_err(|err| format!("invalid account identifier {text}: {err}"))?
+            .try_into()
+            .map_err(|_| format!("account identifier {text} is not 32 bytes long"))?;
+
+        let (checksum, hash) = bytes.split_at(4);
+        if crc32fast::hash(hash).to_be_bytes() != checksum {
+            return Err(format!("account identifier {text} has an invalid checksum"));
+        }
+
+        Ok(AccountIdentifier(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for AccountIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct Tokens {
+    pub e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TimeStamp {
+    pub timestamp_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferArgs {
+    pub memo: u64,
+    pub amount: Tokens,
+    pub fee: Tokens,
+    pub from_subaccount: Option<ByteBuf>,
+    pub to: ByteBuf,
+    pub created_at_time: Option<TimeStamp>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+/// Calls `transfer` on the ICP ledger. Returns the block index of the transfer.
+pub async fn transfer(
+    ledger: Principal,
+    args: TransferArgs,
+) -> Result<Result<u64, TransferError>, (i32, String)> {
+    let (result,): (Result<u64, TransferError>,) =
+        CdkRuntime.call(ledger, "transfer", (args,)).await?;
+    Ok(result)
+}
+
+/// Encodes the account identifier of `hash` with its checksum, as the ICP ledger does.
+#[cfg(test)]
+pub fn account_identifier_hex(hash: [u8; 28]) -> String {
+    let mut bytes = crc32fast::hash(&hash).to_be_bytes().to_vec();
+    bytes.extend_from_slice(&hash);
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_account_identifier() {
+        let text = account_identifier_hex([7; 28]);
+        let id = AccountIdentifier::from_hex(&text).unwrap();
+        assert_eq!(&id.as_bytes()[4..], &[7; 28]);
+        assert_eq!(id.to_string(), text);
+        assert_eq!(AccountIdentifier::from_hex(&text.to_uppercase()), Ok(id));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        let mut text = account_identifier_hex([7; 28]);
+        text.replace_range(62.., "00");
+        assert!(AccountIdentifier::from_hex(&text).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_length() {
+        let text = account_identifier_hex([7; 28]);
+        assert!(AccountIdentifier::from_hex(&text[..62]).is_err());
+        assert!(AccountIdentifier::from_hex(&format!("{text}00")).is_err());
+        assert!(AccountIdentifier::from_hex("zz").is_err());
+    }
+}
@@ -1,28 +1,40 @@
 use candid::CandidType;
 use candid::{Nat, Principal};
-use ic_cdk::api;
-use ic_cdk::{init, query, update};
+use ic_cdk::api::{self, is_controller};
+use ic_cdk::{init, post_upgrade, query, update};
 use ic_http_certification::{HttpRequest, HttpResponse, StatusCode};
 use ic_rmcp::{
     model::*, schema_for_type, Context, Error, Handler, IssuerConfig, OAuthConfig, Server,
 };
 use icrc_ledger_client::ICRC1Client;
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
 use icrc_ledger_types::icrc1::{
-    account::{principal_to_subaccount, Account},
-    transfer::TransferArg,
+    account::{principal_to_subaccount, Account, Subaccount},
+    transfer::{Memo, TransferArg, TransferError},
 };
-use rust_decimal::Decimal;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::{from_value, Value};
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 
+mod account;
+mod amount;
+mod icp_ledger;
+mod repo;
 mod runtime;
 
-use runtime::CdkRuntime;
+use account::{parse_destination, Destination};
+use amount::{format_amount, parse_amount};
+use icp_ledger::{AccountIdentifier, ICP_LEDGER_CANISTER_ID};
+use repo::{
+    get_token, get_transfer, insert_token, insert_transfer, prune_transfers, remove_token,
+    remove_transfer, seed_default_tokens, tokens, TokenInfo, TransferKey, TransferRecord,
+};
+use runtime::{CdkRuntime, Runtime};
+use serde_bytes::ByteBuf;
 
 thread_local! {
-    static TOKENS: RefCell<Vec<(String, String, u8)>> = const{RefCell::new(Vec::new())};
     static ARGS : RefCell<InitArgs> =  RefCell::default();
 }
 
@@ -35,33 +47,19 @@ struct InitArgs {
     authorization_server: Vec<String>,
     audience: String,
     scopes: Vec<String>,
+    /// OAuth subjects allowed to change the token registry, besides the controllers.
+    admins: Option<Vec<String>>,
 }
 
 #[init]
 fn init(config: InitArgs) {
-    TOKENS.with_borrow_mut(|tokens| {
-        tokens.push((
-            "ICP".to_string(),
-            "ryjl3-tyaaa-aaaaa-aaaba-cai".to_string(),
-            8,
-        ));
-        tokens.push((
-            "ckBTC".to_string(),
-            "mxzaz-hqaaa-aaaar-qaada-cai".to_string(),
-            8,
-        ));
-        tokens.push((
-            "ckETH".to_string(),
-            "ss2fx-dyaaa-aaaar-qacoq-cai".to_string(),
-            18,
-        ));
-        tokens.push((
-            "ckUSDC".to_string(),
-            "xevnm-gaaaa-aaaar-qafnq-cai".to_string(),
-            6,
-        ))
-    });
+    seed_default_tokens();
+    ARGS.with_borrow_mut(|args| *args = config);
+}
 
+#[post_upgrade]
+fn post_upgrade(config: InitArgs) {
+    seed_default_tokens();
     ARGS.with_borrow_mut(|args| *args = config);
 }
 
@@ -69,14 +67,28 @@ struct TokenTransferring;
 
 #[derive(JsonSchema, Deserialize)]
 struct TransferRequest {
+    /// A principal, an ICRC-1 account in textual form ("principal-checksum.subaccount"), or,
+    /// for ICP only, a legacy account identifier of 64 hex characters.
     to: String,
-    amount: u64,
-    ledger_canister_id: String,
+    /// Subaccount of the destination principal in hex, up to 64 characters.
+    subaccount: Option<String>,
+    /// Decimal amount of the token, such as "1.5".
+    amount: String,
+    /// Symbol of a supported token, such as "ckBTC", or the canister id of its ledger.
+    #[serde(alias = "ledger_canister_id")]
+    token: String,
+    /// Text of at most 32 bytes, or a number for a transfer to an account identifier.
+    memo: Option<String>,
+    /// Any unique text, such as a UUID. Repeating a transfer with the same key within 24 hours
+    /// returns the first result instead of transferring again.
+    idempotency_key: Option<String>,
 }
 
 #[derive(JsonSchema, Deserialize)]
 struct GetBalanceRequest {
-    ledger_canister_id: String,
+    /// Symbol of a supported token, such as "ckBTC", or the canister id of its ledger.
+    #[serde(alias = "ledger_canister_id")]
+    token: String,
 }
 
 #[derive(JsonSchema, Deserialize)]
@@ -85,6 +97,343 @@ struct AddTokenRequest {
     ledger_canister_id: String,
 }
 
+#[derive(JsonSchema, Deserialize)]
+struct UpdateTokenRequest {
+    ledger_canister_id: String,
+    /// New display name. Keeps the current name when omitted.
+    name: Option<String>,
+}
+
+#[derive(JsonSchema, Deserialize)]
+struct RemoveTokenRequest {
+    ledger_canister_id: String,
+}
+
+/// A registry entry as returned by `get_supported_tokens`.
+#[derive(Serialize)]
+struct SupportedToken {
+    ledger_canister_id: String,
+    name: String,
+    symbol: String,
+    decimals: u8,
+    fee: String,
+    logo: Option<String>,
+    standards: Vec<String>,
+}
+
+impl SupportedToken {
+    fn new(ledger: Principal, info: TokenInfo) -> Self {
+        SupportedToken {
+            ledger_canister_id: ledger.to_text(),
+            name: info.name,
+            symbol: info.symbol,
+            decimals: info.decimals,
+            fee: info.fee.0.to_string(),
+            logo: info.logo,
+            standards: info.standards,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct StandardRecord {
+    name: String,
+    #[allow(dead_code)]
+    url: String,
+}
+
+/// A token given by its symbol in the token list or by the canister id of its ledger.
+struct ResolvedToken {
+    client: ICRC1Client<CdkRuntime>,
+    symbol: String,
+    decimals: u8,
+}
+
+/// Looks the token up by symbol first, ignoring case. A ledger outside the token list is
+/// accepted by id, with its symbol and decimals read from the ledger.
+async fn resolve_token(token: &str) -> Result<ResolvedToken, Error> {
+    let token = token.trim();
+    let mut listed = tokens()
+        .into_iter()
+        .filter(|(_, info)| info.symbol.eq_ignore_ascii_case(token));
+
+    let (ledger, info) = match (listed.next(), listed.next()) {
+        (Some((ledger, info)), None) => (ledger, Some(info)),
+        (Some(_), Some(_)) => {
+            return Err(Error::invalid_params(
+                format!("several tokens have the symbol {token}. Use the ledger canister id"),
+                None,
+            ))
+        }
+        (None, _) => {
+            let ledger = Principal::from_text(token).map_err(|_| {
+                Error::invalid_params(
+                    format!(
+                        "unknown token {token}. Use get_supported_tokens to list the supported tokens"
+                    ),
+                    None,
+                )
+            })?;
+            (ledger, get_token(ledger))
+        }
+    };
+
+    let client = ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id: ledger,
+    };
+    let (symbol, decimals) = match info {
+        Some(info) => (info.symbol, info.decimals),
+        None => (
+            client
+                .symbol()
+                .await
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?,
+            client
+                .decimals()
+                .await
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?,
+        ),
+    };
+
+    Ok(ResolvedToken {
+        client,
+        symbol,
+        decimals,
+    })
+}
+
+/// Longest memo ICRC-1 ledgers accept by default.
+const MAX_MEMO_BYTES: usize = 32;
+
+const MAX_IDEMPOTENCY_KEY_BYTES: usize = 128;
+
+/// The memo of a transfer: the given one, or else one derived from the idempotency key. The
+/// ICP ledger's `transfer` takes a 64-bit memo, kept as its big-endian bytes.
+fn transfer_memo(
+    destination: &Destination,
+    memo: Option<&str>,
+    idempotency_key: Option<&str>,
+) -> Result<Option<Vec<u8>>, Error> {
+    let legacy = matches!(destination, Destination::AccountIdentifier(_));
+
+    match (memo, idempotency_key) {
+        (Some(memo), _) if legacy => memo
+            .trim()
+            .parse::<u64>()
+            .map(|memo| Some(memo.to_be_bytes().to_vec()))
+            .map_err(|_| {
+                Error::invalid_params(
+                    "the memo of a transfer to an account identifier must be a number",
+                    None,
+                )
+            }),
+        (Some(memo), _) => {
+            if memo.len() > MAX_MEMO_BYTES {
+                return Err(Error::invalid_params(
+                    format!("memo must be at most {MAX_MEMO_BYTES} bytes"),
+                    None,
+                ));
+            }
+            Ok(Some(memo.as_bytes().to_vec()))
+        }
+        (None, Some(key)) => {
+            let mut hasher = Sha256::new();
+            hasher.update(key.as_bytes());
+            let hash = hasher.finalize();
+            Ok(Some(if legacy {
+                hash[..8].to_vec()
+            } else {
+                hash.to_vec()
+            }))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// A transfer whose amount and fee are checked against its ledger, ready to be sent.
+enum PreparedTransfer<'a> {
+    Account(&'a Account),
+    /// The ICP ledger's `transfer` takes the amount in a `u64` and the fee explicitly.
+    AccountIdentifier {
+        to: &'a AccountIdentifier,
+        e8s: u64,
+        fee: u64,
+    },
+}
+
+/// Does everything a transfer to `destination` needs before the ledger is asked to execute it,
+/// so an error here leaves nothing to deduplicate.
+async fn prepare_transfer<'a>(
+    token: &ResolvedToken,
+    destination: &'a Destination,
+    amount: &Nat,
+) -> Result<PreparedTransfer<'a>, Error> {
+    match destination {
+        Destination::Account(to) => Ok(PreparedTransfer::Account(to)),
+        Destination::AccountIdentifier(to) => {
+            let e8s = u64::try_from(amount.0.clone()).map_err(|_| {
+                Error::invalid_params("amount is too large for the ICP ledger", None)
+            })?;
+            let fee = token
+                .client
+                .fee()
+                .await
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+            let fee = u64::try_from(fee.0)
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+            Ok(PreparedTransfer::AccountIdentifier { to, e8s, fee })
+        }
+    }
+}
+
+/// Sends a transfer from the subaccount of a user. Returns the block index, also when the
+/// ledger reports the transfer as a duplicate of an earlier one, or why the ledger rejected it.
+/// A failed call leaves the outcome unknown.
+async fn send_transfer(
+    token: &ResolvedToken,
+    transfer: PreparedTransfer<'_>,
+    from_subaccount: Subaccount,
+    amount: &Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+) -> Result<Result<Nat, String>, Error> {
+    match transfer {
+        PreparedTransfer::Account(to) => {
+            let result = token
+                .client
+                .transfer(TransferArg {
+                    to: *to,
+                    fee: None,
+                    memo: memo.map(|memo| Memo::from(ByteBuf::from(memo))),
+                    from_subaccount: Some(from_subaccount),
+                    created_at_time,
+                    amount: amount.clone(),
+                })
+                .await
+                .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+            Ok(match result {
+                Ok(block_index)
+                | Err(TransferError::Duplicate {
+                    duplicate_of: block_index,
+                }) => Ok(block_index),
+                Err(err) => Err(format!("{err:?}")),
+            })
+        }
+        PreparedTransfer::AccountIdentifier { to, e8s, fee } => {
+            let memo = memo.map_or(0, |memo| {
+                u64::from_be_bytes(memo.try_into().expect("legacy memos are 8 bytes"))
+            });
+
+            let result = icp_ledger::transfer(
+                token.client.ledger_canister_id,
+                icp_ledger::TransferArgs {
+                    memo,
+                    amount: icp_ledger::Tokens { e8s },
+                    fee: icp_ledger::Tokens { e8s: fee },
+                    from_subaccount: Some(ByteBuf::from(from_subaccount.to_vec())),
+                    to: ByteBuf::from(to.as_bytes().to_vec()),
+                    created_at_time: created_at_time
+                        .map(|timestamp_nanos| icp_ledger::TimeStamp { timestamp_nanos }),
+                },
+            )
+            .await
+            .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+            Ok(match result {
+                Ok(block_index)
+                | Err(icp_ledger::TransferError::TxDuplicate {
+                    duplicate_of: block_index,
+                }) => Ok(Nat::from(block_index)),
+                Err(err) => Err(format!("{err:?}")),
+            })
+        }
+    }
+}
+
+/// Only the admins and the controllers of the canister may change the token registry, which
+/// all users share.
+fn require_admin(subject: Option<String>) -> Result<(), Error> {
+    let subject = subject.ok_or(Error::internal_error("no subject".to_string(), None))?;
+    let is_admin = ARGS.with_borrow(|args| args.admins.iter().flatten().any(|a| *a == subject));
+    // Subjects of ordinary OAuth providers are not principals, and so not controllers.
+    let is_controller = || Principal::from_text(&subject).is_ok_and(|p| is_controller(&p));
+
+    if !is_admin && !is_controller() {
+        return Err(Error::invalid_request(
+            "Forbidden: only admins and controllers of the canister can change the token list",
+            None,
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_name(name: String) -> Result<String, Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::invalid_params("token name must not be empty", None));
+    }
+
+    Ok(name.to_string())
+}
+
+fn parse_ledger(ledger_canister_id: &str) -> Result<ICRC1Client<CdkRuntime>, Error> {
+    Ok(ICRC1Client {
+        runtime: CdkRuntime,
+        ledger_canister_id: Principal::from_text(ledger_canister_id)
+            .map_err(|_| Error::invalid_params("invalid ledger canister id", None))?,
+    })
+}
+
+/// Reads the registry entry of a token from its ledger, under the given display name. A
+/// canister that does not answer the ICRC-1 standards, symbol and fee queries, or does not
+/// list ICRC-1 among its standards, is rejected.
+async fn fetch_token_info(
+    client: &ICRC1Client<CdkRuntime>,
+    name: String,
+) -> Result<TokenInfo, Error> {
+    let ledger = client.ledger_canister_id;
+    let not_a_ledger = |err: (i32, String)| {
+        Error::invalid_params(format!("{ledger} is not an ICRC-1 ledger: {err:?}"), None)
+    };
+
+    let (standards,): (Vec<StandardRecord>,) = client
+        .runtime
+        .call(ledger, "icrc1_supported_standards", ())
+        .await
+        .map_err(not_a_ledger)?;
+    if !standards.iter().any(|s| s.name == "ICRC-1") {
+        return Err(Error::invalid_params(
+            format!("{ledger} does not support ICRC-1"),
+            None,
+        ));
+    }
+
+    let symbol = client.symbol().await.map_err(not_a_ledger)?;
+    let fee = client.fee().await.map_err(not_a_ledger)?;
+    let decimals = client.decimals().await.map_err(not_a_ledger)?;
+    let logo = client
+        .metadata()
+        .await
+        .map_err(not_a_ledger)?
+        .into_iter()
+        .find_map(|(key, value)| match value {
+            MetadataValue::Text(logo) if key == "icrc1:logo" => Some(logo),
+            _ => None,
+        });
+
+    Ok(TokenInfo {
+        name,
+        symbol,
+        decimals,
+        fee,
+        logo,
+        standards: standards.into_iter().map(|s| s.name).collect(),
+    })
+}
+
 impl Handler for TokenTransferring {
     async fn call_tool(
         &self,
@@ -100,21 +449,7 @@ impl Handler for TokenTransferring {
                     Error::invalid_params("invalid arguments to tool get_balance", None)
                 })?;
 
-                let client = ICRC1Client {
-                    runtime: CdkRuntime,
-                    ledger_canister_id: Principal::from_text(request.ledger_canister_id)
-                        .map_err(|_| Error::invalid_params("invalid ledger canister id", None))?,
-                };
-
-                let symbol = client
-                    .symbol()
-                    .await
-                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
-
-                let decimals = client
-                    .decimals()
-                    .await
-                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+                let token = resolve_token(&request.token).await?;
 
                 let subject = context
                     .subject
@@ -125,7 +460,8 @@ impl Handler for TokenTransferring {
                         .map_err(|err| Error::internal_error(format!("{err:?}"), None))?,
                 );
 
-                let balance = client
+                let balance = token
+                    .client
                     .balance_of(Account {
                         owner: api::canister_self(),
                         subaccount: Some(subaccount),
@@ -136,11 +472,8 @@ impl Handler for TokenTransferring {
                 Ok(CallToolResult::success(
                     Content::text(format!(
                         "The balance is {} {}",
-                        Decimal::from_i128_with_scale(
-                            i128::try_from(balance.0).unwrap(),
-                            decimals as u32
-                        ),
-                        symbol
+                        format_amount(&balance, token.decimals),
+                        token.symbol
                     ))
                     .into_contents(),
                 ))
@@ -151,44 +484,147 @@ impl Handler for TokenTransferring {
                 )?))
                 .map_err(|_| Error::invalid_params("invalid arguments to tool transfer", None))?;
 
-                let recipiant = Principal::from_text(req.to).map_err(|_| {
-                    Error::invalid_params("invalid principal in tool transfer", None)
-                })?;
-
-                let client = ICRC1Client {
-                    runtime: CdkRuntime,
-                    ledger_canister_id: Principal::from_text(req.ledger_canister_id)
-                        .map_err(|_| Error::invalid_params("invalid ledger canister id", None))?,
-                };
+                let destination = parse_destination(&req.to, req.subaccount.as_deref())
+                    .map_err(|err| Error::invalid_params(err, None))?;
+
+                let token = resolve_token(&req.token).await?;
+                let ledger = token.client.ledger_canister_id;
+                if matches!(destination, Destination::AccountIdentifier(_))
+                    && ledger.to_text() != ICP_LEDGER_CANISTER_ID
+                {
+                    return Err(Error::invalid_params(
+                        format!(
+                            "account identifiers are only supported for ICP. Give {} an ICRC-1 account",
+                            token.symbol
+                        ),
+                        None,
+                    ));
+                }
+
+                let amount = parse_amount(&req.amount, token.decimals)
+                    .map_err(|err| Error::invalid_params(err, None))?;
+                let memo = transfer_memo(
+                    &destination,
+                    req.memo.as_deref(),
+                    req.idempotency_key.as_deref(),
+                )?;
 
                 let subject = context
                     .subject
                     .ok_or(Error::internal_error("no subject".to_string(), None))?;
 
-                let subaccount = principal_to_subaccount(
-                    Principal::from_text(subject)
-                        .map_err(|err| Error::internal_error(format!("{err:?}"), None))?,
-                );
-
-                let _ = client
-                    .transfer(TransferArg {
-                        to: Account {
-                            owner: recipiant,
-                            subaccount: None,
-                        },
-                        fee: None,
-                        memo: None,
-                        from_subaccount: Some(subaccount),
-                        created_at_time: None,
-                        amount: Nat::from(req.amount),
-                    })
-                    .await
-                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?
+                let user = Principal::from_text(subject)
                     .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+                let subaccount = principal_to_subaccount(user);
+
+                let transferred = |block_index: &Nat| {
+                    format!(
+                        "Transferred {} {} to {} in block {}",
+                        format_amount(&amount, token.decimals),
+                        token.symbol,
+                        destination,
+                        block_index.0
+                    )
+                };
 
-                Ok(CallToolResult::success(
-                    Content::text("Success").into_contents(),
-                ))
+                let Some(idempotency_key) = req.idempotency_key else {
+                    let transfer = prepare_transfer(&token, &destination, &amount).await?;
+                    let block_index =
+                        send_transfer(&token, transfer, subaccount, &amount, memo, None)
+                            .await?
+                            .map_err(|err| Error::internal_error(err, None))?;
+
+                    return Ok(CallToolResult::success(
+                        Content::text(transferred(&block_index)).into_contents(),
+                    ));
+                };
+
+                if idempotency_key.is_empty() || idempotency_key.len() > MAX_IDEMPOTENCY_KEY_BYTES {
+                    return Err(Error::invalid_params(
+                        format!("idempotency key must be 1 to {MAX_IDEMPOTENCY_KEY_BYTES} bytes"),
+                        None,
+                    ));
+                }
+
+                // Checked before the key is recorded, so a transfer the ledger never saw does not
+                // hold the key.
+                let transfer = prepare_transfer(&token, &destination, &amount).await?;
+
+                let now = api::time();
+                prune_transfers(now);
+
+                let key = TransferKey::new(user, &idempotency_key);
+                let record = match get_transfer(&key) {
+                    Some(record) => {
+                        if record.ledger != ledger
+                            || record.to != destination.to_string()
+                            || record.amount != amount
+                            || record.memo != memo
+                        {
+                            return Err(Error::invalid_params(
+                                format!(
+                                    "idempotency key {idempotency_key} was already used for a different transfer"
+                                ),
+                                None,
+                            ));
+                        }
+
+                        if let Some(block_index) = &record.block_index {
+                            return Ok(CallToolResult::success(
+                                Content::text(format!(
+                                    "{}. Already done with idempotency key {idempotency_key}; nothing was transferred again",
+                                    transferred(block_index)
+                                ))
+                                .into_contents(),
+                            ));
+                        }
+
+                        // The reply to an earlier attempt was lost. Sending it again with the
+                        // same created_at_time and memo lets the ledger deduplicate it.
+                        record
+                    }
+                    None => {
+                        let record = TransferRecord {
+                            created_at_time: now,
+                            ledger,
+                            to: destination.to_string(),
+                            amount: amount.clone(),
+                            memo: memo.clone(),
+                            block_index: None,
+                        };
+                        insert_transfer(key, record.clone());
+                        record
+                    }
+                };
+
+                // A failed call keeps the record, so a retry reuses its created_at_time.
+                match send_transfer(
+                    &token,
+                    transfer,
+                    subaccount,
+                    &amount,
+                    memo,
+                    Some(record.created_at_time),
+                )
+                .await?
+                {
+                    Ok(block_index) => {
+                        insert_transfer(
+                            key,
+                            TransferRecord {
+                                block_index: Some(block_index.clone()),
+                                ..record
+                            },
+                        );
+                        Ok(CallToolResult::success(
+                            Content::text(transferred(&block_index)).into_contents(),
+                        ))
+                    }
+                    Err(err) => {
+                        remove_transfer(&key);
+                        Err(Error::internal_error(err, None))
+                    }
+                }
             }
             "get_account_address" => {
                 let subject = context
@@ -217,28 +653,111 @@ impl Handler for TokenTransferring {
                 )?))
                 .map_err(|_| Error::invalid_params("invalid arguments to tool add_token", None))?;
 
-                let client = ICRC1Client {
-                    runtime: CdkRuntime,
-                    ledger_canister_id: Principal::from_text(request.ledger_canister_id.clone())
-                        .map_err(|_| Error::invalid_params("invalid ledger canister id", None))?,
+                require_admin(context.subject)?;
+
+                let name = validate_name(request.name)?;
+                let client = parse_ledger(&request.ledger_canister_id)?;
+
+                if let Some(existing) = get_token(client.ledger_canister_id) {
+                    return Err(Error::invalid_params(
+                        format!(
+                            "{} is already in the token list as {}. Use update_token to refresh it",
+                            client.ledger_canister_id, existing.symbol
+                        ),
+                        None,
+                    ));
+                }
+
+                let info = fetch_token_info(&client, name).await?;
+
+                // The ledger calls above may have let a concurrent add_token through.
+                if get_token(client.ledger_canister_id).is_some() {
+                    return Err(Error::invalid_params(
+                        format!("{} is already in the token list", client.ledger_canister_id),
+                        None,
+                    ));
+                }
+
+                let message = format!("Added {} ({})", info.symbol, client.ledger_canister_id);
+                insert_token(client.ledger_canister_id, info);
+                Ok(CallToolResult::success(
+                    Content::text(message).into_contents(),
+                ))
+            }
+            "update_token" => {
+                let request =
+                    from_value::<UpdateTokenRequest>(Value::Object(req.arguments.ok_or(
+                        Error::invalid_params("invalid arguments to tool update_token", None),
+                    )?))
+                    .map_err(|_| {
+                        Error::invalid_params("invalid arguments to tool update_token", None)
+                    })?;
+
+                require_admin(context.subject)?;
+
+                let client = parse_ledger(&request.ledger_canister_id)?;
+                let current = get_token(client.ledger_canister_id).ok_or(Error::invalid_params(
+                    format!("{} is not in the token list", client.ledger_canister_id),
+                    None,
+                ))?;
+                let name = match request.name {
+                    Some(name) => validate_name(name)?,
+                    None => current.name,
                 };
 
-                let decimals = client
-                    .decimals()
-                    .await
-                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+                let info = fetch_token_info(&client, name).await?;
+
+                // Do not bring back a token removed during the ledger calls.
+                if get_token(client.ledger_canister_id).is_none() {
+                    return Err(Error::invalid_params(
+                        format!("{} is not in the token list", client.ledger_canister_id),
+                        None,
+                    ));
+                }
 
-                TOKENS.with_borrow_mut(|tokens| {
-                    tokens.push((request.name, request.ledger_canister_id, decimals));
-                });
+                let message = format!("Updated {} ({})", info.symbol, client.ledger_canister_id);
+                insert_token(client.ledger_canister_id, info);
                 Ok(CallToolResult::success(
-                    Content::text("Success").into_contents(),
+                    Content::text(message).into_contents(),
                 ))
             }
-            "get_supported_tokens" => Ok(CallToolResult::success(
-                Content::text(format!("{:?}", TOKENS.with_borrow(|tokens| tokens.clone())))
+            "remove_token" => {
+                let request =
+                    from_value::<RemoveTokenRequest>(Value::Object(req.arguments.ok_or(
+                        Error::invalid_params("invalid arguments to tool remove_token", None),
+                    )?))
+                    .map_err(|_| {
+                        Error::invalid_params("invalid arguments to tool remove_token", None)
+                    })?;
+
+                require_admin(context.subject)?;
+
+                let client = parse_ledger(&request.ledger_canister_id)?;
+                let removed =
+                    remove_token(client.ledger_canister_id).ok_or(Error::invalid_params(
+                        format!("{} is not in the token list", client.ledger_canister_id),
+                        None,
+                    ))?;
+
+                Ok(CallToolResult::success(
+                    Content::text(format!(
+                        "Removed {} ({})",
+                        removed.symbol, client.ledger_canister_id
+                    ))
                     .into_contents(),
-            )),
+                ))
+            }
+            "get_supported_tokens" => {
+                let tokens: Vec<SupportedToken> = tokens()
+                    .into_iter()
+                    .map(|(ledger, info)| SupportedToken::new(ledger, info))
+                    .collect();
+
+                let content = Content::json(tokens)
+                    .map_err(|err| Error::internal_error(format!("{err:?}"), None))?;
+
+                Ok(CallToolResult::success(content.into_contents()))
+            }
             _ => Err(Error::invalid_params("not found tool", None)),
         }
     }
@@ -252,15 +771,17 @@ impl Handler for TokenTransferring {
             tools: vec![
                 Tool::new(
                     "get_balance",
-                    "Get ICRC-1 token balance for your account. The subaccount will be derived from user's authenticated identity.",
+                    "Get ICRC-1 token balance for your account. The subaccount will be derived from user's authenticated identity. The token is given by its symbol in the token list or by its ledger canister ID.",
                     schema_for_type::<GetBalanceRequest>(),
                 ),
                 Tool::new("transfer",
-                 "Transfer ICRC-1 token from your account to the given destination principal. The unit is in decimal format. For example, to transfer 1.5 ICP, you should pass 150000000 as amount. To get decimals value, use get_supported_tokens tool.",
+                 "Transfer ICRC-1 token from your account to the given destination: a principal, optionally with a hex subaccount, an ICRC-1 textual account (principal-checksum.subaccount), or for ICP a 64 hex characters account identifier, as exchanges use for deposits. The token is given by its symbol in the token list or by its ledger canister ID. The amount is a decimal string in whole tokens: to transfer 1.5 ICP, pass \"1.5\". Amounts with more decimal places than the token has are rejected. The ledger fee is charged on top of the amount. Pass an idempotency_key when the call may be retried: a repeated key within 24 hours returns the first result instead of transferring twice.",
                 schema_for_type::<TransferRequest>()),
                 Tool::new("get_account_address", "Get your address. Basically, it consists of a subaccount mapped from authenticated identity, under the server principal. Use this tool when you need to top up ICRC-1 token to your account.", schema_for_type::<EmptyObject>()),
-                Tool::new("add_token", "Add new token to token list.", schema_for_type::<AddTokenRequest>()),
-                Tool::new("get_supported_tokens", "Return a list of supported tokens. Use this when retrieving token's ledger canister ID and its decimals.", schema_for_type::<EmptyObject>())
+                Tool::new("add_token", "Add new token to token list. Its symbol, decimals, fee, logo and supported standards are read from the ledger, which must support ICRC-1. Only controllers of the server canister can use this tool.", schema_for_type::<AddTokenRequest>()),
+                Tool::new("update_token", "Refresh a token of the token list from its ledger, and optionally rename it. Only controllers of the server canister can use this tool.", schema_for_type::<UpdateTokenRequest>()),
+                Tool::new("remove_token", "Remove a token from the token list. Only controllers of the server canister can use this tool.", schema_for_type::<RemoveTokenRequest>()),
+                Tool::new("get_supported_tokens", "Return a list of supported tokens. Use this when retrieving token's ledger canister ID, its decimals and its transfer fee.", schema_for_type::<EmptyObject>())
             ],
         })
     }
@@ -312,3 +833,17 @@ async fn http_request_update(req: HttpRequest<'_>) -> HttpResponse<'_> {
 }
 
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admins_are_oauth_subjects() {
+        ARGS.with_borrow_mut(|args| args.admins = Some(vec!["google-oauth2|1234".to_string()]));
+
+        assert!(require_admin(Some("google-oauth2|1234".to_string())).is_ok());
+        assert!(require_admin(Some("google-oauth2|5678".to_string())).is_err());
+        assert!(require_admin(None).is_err());
+    }
+}
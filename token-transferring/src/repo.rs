@@ -0,0 +1,397 @@
+use candid::{CandidType, Decode, Encode, Nat, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{
+    storable::Bound, DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// A token the server can transfer, as read from its ledger when it was added.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenInfo {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    /// Transfer fee in the smallest unit of the token.
+    pub fee: Nat,
+    /// The `icrc1:logo` metadata of the ledger, usually a data URL.
+    pub logo: Option<String>,
+    /// Standards reported by `icrc1_supported_standards`, such as `ICRC-1` and `ICRC-2`.
+    pub standards: Vec<String>,
+}
+
+impl Storable for TokenInfo {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// How long ICRC-1 ledgers deduplicate transfers by `created_at_time`, and so how long an
+/// idempotency key is remembered.
+pub const TRANSFER_DEDUP_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Hash of a user and an idempotency key the user chose, so keys of different users never
+/// collide.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TransferKey([u8; 32]);
+
+impl TransferKey {
+    pub fn new(user: Principal, idempotency_key: &str) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update([user.as_slice().len() as u8]);
+        hasher.update(user.as_slice());
+        hasher.update(idempotency_key.as_bytes());
+        TransferKey(hasher.finalize().into())
+    }
+}
+
+impl Storable for TransferKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        TransferKey(bytes.as_ref().try_into().unwrap())
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+}
+
+/// A transfer made with an idempotency key. Retries reuse `created_at_time` and `memo`, so the
+/// ledger rejects them as duplicates of the first attempt.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransferRecord {
+    pub created_at_time: u64,
+    pub ledger: Principal,
+    pub to: String,
+    pub amount: Nat,
+    pub memo: Option<Vec<u8>>,
+    /// Unset until the ledger answered, so a transfer whose reply was lost is sent again.
+    pub block_index: Option<Nat>,
+}
+
+impl Storable for TransferRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Version of the registry written by this release. Version 0 is a fresh install, or an
+/// upgrade from a release that kept the token list on the heap and lost it on upgrade.
+const REGISTRY_VERSION: u8 = 1;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    // Supported tokens keyed by ledger canister id.
+    static TOKENS: RefCell<StableBTreeMap<Principal, TokenInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+        )
+    );
+
+    static TOKENS_VERSION: RefCell<StableCell<u8, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+            0,
+        )
+        .expect("failed to initialize token registry version")
+    );
+
+    static TRANSFERS: RefCell<StableBTreeMap<TransferKey, TransferRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+        )
+    );
+
+    // Keys of `TRANSFERS` by (created_at_time, key), to forget them once the window is over.
+    static TRANSFERS_BY_TIME: RefCell<StableBTreeMap<(u64, TransferKey), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+}
+
+/// Tokens supported out of the box, with their fees at the time of writing.
+fn default_tokens() -> Vec<(Principal, TokenInfo)> {
+    let icrc = || vec!["ICRC-1".to_string(), "ICRC-2".to_string()];
+    let token = |name: &str, symbol: &str, decimals: u8, fee: u128| TokenInfo {
+        name: name.to_string(),
+        symbol: symbol.to_string(),
+        decimals,
+        fee: Nat::from(fee),
+        logo: None,
+        standards: icrc(),
+    };
+
+    [
+        (
+            "ryjl3-tyaaa-aaaaa-aaaba-cai",
+            token("Internet Computer", "ICP", 8, 10_000),
+        ),
+        (
+            "mxzaz-hqaaa-aaaar-qaada-cai",
+            token("ckBTC", "ckBTC", 8, 10),
+        ),
+        (
+            "ss2fx-dyaaa-aaaar-qacoq-cai",
+            token("ckETH", "ckETH", 18, 2_000_000_000_000),
+        ),
+        (
+            "xevnm-gaaaa-aaaar-qafnq-cai",
+            token("ckUSDC", "ckUSDC", 6, 10_000),
+        ),
+    ]
+    .into_iter()
+    .map(|(ledger, info)| (Principal::from_text(ledger).unwrap(), info))
+    .collect()
+}
+
+/// Adds the default tokens the first time the registry is set up, on install or on the
+/// upgrade from the heap-only release. Later upgrades keep the registry as it is, so tokens
+/// added, edited or removed since then stay that way.
+pub fn seed_default_tokens() {
+    if TOKENS_VERSION.with_borrow(|v| *v.get()) >= REGISTRY_VERSION {
+        return;
+    }
+
+    TOKENS.with_borrow_mut(|tokens| {
+        for (ledger, info) in default_tokens() {
+            if !tokens.contains_key(&ledger) {
+                tokens.insert(ledger, info);
+            }
+        }
+    });
+    TOKENS_VERSION.with_borrow_mut(|v| v.set(REGISTRY_VERSION).unwrap());
+}
+
+pub fn get_token(ledger: Principal) -> Option<TokenInfo> {
+    TOKENS.with_borrow(|tokens| tokens.get(&ledger))
+}
+
+/// All supported tokens, ordered by ledger canister id.
+pub fn tokens() -> Vec<(Principal, TokenInfo)> {
+    TOKENS.with_borrow(|tokens| tokens.iter().collect())
+}
+
+/// Adds a token, or replaces the stored one of the same ledger. Returns the replaced token.
+pub fn insert_token(ledger: Principal, info: TokenInfo) -> Option<TokenInfo> {
+    TOKENS.with_borrow_mut(|tokens| tokens.insert(ledger, info))
+}
+
+pub fn remove_token(ledger: Principal) -> Option<TokenInfo> {
+    TOKENS.with_borrow_mut(|tokens| tokens.remove(&ledger))
+}
+
+pub fn get_transfer(key: &TransferKey) -> Option<TransferRecord> {
+    TRANSFERS.with_borrow(|transfers| transfers.get(key))
+}
+
+/// Stores a transfer, or the ledger reply of a stored one. `created_at_time` of a key must
+/// not change.
+pub fn insert_transfer(key: TransferKey, record: TransferRecord) {
+    TRANSFERS_BY_TIME.with_borrow_mut(|by_time| by_time.insert((record.created_at_time, key), ()));
+    TRANSFERS.with_borrow_mut(|transfers| transfers.insert(key, record));
+}
+
+/// Forgets a transfer the ledger rejected, so its key can be used again.
+pub fn remove_transfer(key: &TransferKey) {
+    if let Some(record) = TRANSFERS.with_borrow_mut(|transfers| transfers.remove(key)) {
+        TRANSFERS_BY_TIME
+            .with_borrow_mut(|by_time| by_time.remove(&(record.created_at_time, *key)));
+    }
+}
+
+/// Forgets the transfers the ledgers no longer deduplicate.
+pub fn prune_transfers(now: u64) {
+    let cutoff = now.saturating_sub(TRANSFER_DEDUP_WINDOW_NANOS);
+    while let Some(((created_at_time, key), _)) =
+        TRANSFERS_BY_TIME.with_borrow(|by_time| by_time.first_key_value())
+    {
+        if created_at_time >= cutoff {
+            break;
+        }
+        TRANSFERS_BY_TIME.with_borrow_mut(|by_time| by_time.remove(&(created_at_time, key)));
+        TRANSFERS.with_borrow_mut(|transfers| transfers.remove(&key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ledger(text: &str) -> Principal {
+        Principal::from_text(text).unwrap()
+    }
+
+    fn token(symbol: &str) -> TokenInfo {
+        TokenInfo {
+            name: format!("{symbol} token"),
+            symbol: symbol.to_string(),
+            decimals: 8,
+            fee: Nat::from(10_000u64),
+            logo: Some("data:image/svg+xml;base64,PHN2Zy8+".to_string()),
+            standards: vec!["ICRC-1".to_string()],
+        }
+    }
+
+    /// The registry as a freshly upgraded canister sees it: a new map over the same memory.
+    fn reloaded_tokens() -> Vec<(Principal, TokenInfo)> {
+        let tokens: StableBTreeMap<Principal, TokenInfo, Memory> =
+            StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))));
+        tokens.iter().collect()
+    }
+
+    fn transfer(created_at_time: u64) -> TransferRecord {
+        TransferRecord {
+            created_at_time,
+            ledger: ledger("ryjl3-tyaaa-aaaaa-aaaba-cai"),
+            to: "rdmx6-jaaaa-aaaaa-aaadq-cai".to_string(),
+            amount: Nat::from(150_000_000u64),
+            memo: Some(vec![1, 2, 3]),
+            block_index: None,
+        }
+    }
+
+    #[test]
+    fn token_info_round_trips() {
+        let info = token("TKN");
+        assert_eq!(TokenInfo::from_bytes(info.to_bytes()), info);
+    }
+
+    #[test]
+    fn install_seeds_default_tokens() {
+        seed_default_tokens();
+
+        let symbols: Vec<String> = tokens().into_iter().map(|(_, t)| t.symbol).collect();
+        assert_eq!(symbols.len(), 4);
+        for symbol in ["ICP", "ckBTC", "ckETH", "ckUSDC"] {
+            assert!(symbols.iter().any(|s| s == symbol));
+        }
+        let cketh = ledger("ss2fx-dyaaa-aaaar-qacoq-cai");
+        assert!(tokens()
+            .iter()
+            .any(|(l, t)| *l == cketh && t.decimals == 18));
+    }
+
+    #[test]
+    fn added_tokens_survive_upgrade() {
+        seed_default_tokens();
+        let added = ledger("rdmx6-jaaaa-aaaaa-aaadq-cai");
+        insert_token(added, token("TKN"));
+
+        // post_upgrade
+        seed_default_tokens();
+
+        let tokens = reloaded_tokens();
+        assert_eq!(tokens.len(), 5);
+        assert!(tokens.contains(&(added, token("TKN"))));
+    }
+
+    #[test]
+    fn upgrade_does_not_reseed_defaults() {
+        seed_default_tokens();
+        let icp = ledger("ryjl3-tyaaa-aaaaa-aaaba-cai");
+        insert_token(icp, token("ICP"));
+
+        // post_upgrade
+        seed_default_tokens();
+
+        assert!(reloaded_tokens().contains(&(icp, token("ICP"))));
+    }
+
+    #[test]
+    fn removed_default_stays_removed_after_upgrade() {
+        seed_default_tokens();
+        let icp = ledger("ryjl3-tyaaa-aaaaa-aaaba-cai");
+        assert!(remove_token(icp).is_some());
+        assert!(remove_token(icp).is_none());
+
+        // post_upgrade
+        seed_default_tokens();
+
+        assert_eq!(get_token(icp), None);
+        assert_eq!(reloaded_tokens().len(), 3);
+    }
+
+    #[test]
+    fn upgrade_from_heap_registry_seeds_defaults() {
+        // The heap-only release left neither tokens nor a version in stable memory.
+        assert!(reloaded_tokens().is_empty());
+
+        // post_upgrade
+        seed_default_tokens();
+
+        assert_eq!(reloaded_tokens().len(), 4);
+    }
+
+    #[test]
+    fn transfer_record_round_trips() {
+        let record = TransferRecord {
+            block_index: Some(Nat::from(42u64)),
+            ..transfer(1)
+        };
+        assert_eq!(TransferRecord::from_bytes(record.to_bytes()), record);
+    }
+
+    #[test]
+    fn transfer_keys_are_per_user() {
+        let alice = Principal::anonymous();
+        let bob = ledger("rdmx6-jaaaa-aaaaa-aaadq-cai");
+        assert_eq!(TransferKey::new(alice, "k"), TransferKey::new(alice, "k"));
+        assert_ne!(TransferKey::new(alice, "k"), TransferKey::new(bob, "k"));
+        assert_ne!(TransferKey::new(alice, "k"), TransferKey::new(alice, "l"));
+    }
+
+    #[test]
+    fn removed_transfer_frees_its_key() {
+        let key = TransferKey::new(Principal::anonymous(), "k");
+        insert_transfer(key, transfer(1));
+        remove_transfer(&key);
+
+        assert_eq!(get_transfer(&key), None);
+        assert!(TRANSFERS_BY_TIME.with_borrow(|by_time| by_time.is_empty()));
+    }
+
+    #[test]
+    fn prunes_transfers_outside_the_window() {
+        let now = 2 * TRANSFER_DEDUP_WINDOW_NANOS;
+        let old = TransferKey::new(Principal::anonymous(), "old");
+        let recent = TransferKey::new(Principal::anonymous(), "recent");
+        insert_transfer(old, transfer(now - TRANSFER_DEDUP_WINDOW_NANOS - 1));
+        insert_transfer(recent, transfer(now - TRANSFER_DEDUP_WINDOW_NANOS));
+
+        // Recording the reply keeps the entry of the time index.
+        let answered = TransferRecord {
+            block_index: Some(Nat::from(7u64)),
+            ..transfer(now - TRANSFER_DEDUP_WINDOW_NANOS)
+        };
+        insert_transfer(recent, answered.clone());
+
+        prune_transfers(now);
+
+        assert_eq!(get_transfer(&old), None);
+        assert_eq!(get_transfer(&recent), Some(answered));
+        assert_eq!(TRANSFERS_BY_TIME.with_borrow(|by_time| by_time.len()), 1);
+    }
+}